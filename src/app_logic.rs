@@ -1,331 +1,540 @@
 use std::path::Path;
-use rusqlite::{params, Connection};
-use chrono::{DateTime, Duration, Utc};
-use argon2::{self, Config};
-use rand::{distributions::Alphanumeric, Rng};
-use rocket::serde::{Serialize, json::Json};
+use std::time::Duration as StdDuration;
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension, Row, Transaction, TransactionBehavior};
+use rusqlite::types::Value;
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use argon2::{self, Variant};
+use rand::Rng;
+use rocket::serde::Serialize;
+use crate::errors::{AppError, AppResult, FieldError};
+use crate::config::{AppConfig, ContentConfig, HashingConfig, RateLimitConfig};
+use crate::diff::line_diff;
+use crate::keys;
+use crate::mailer::{Email, Mailer};
+use crate::pagination::{Cursor, Page, SortOrder};
+use crate::privileges::{self, Permission};
+use crate::search;
+use crate::validation;
+
+// Constants
+pub const EMAIL_VERIFICATION_TOKEN: &str = "email_verification";
+pub const PASSWORD_RESET_TOKEN: &str = "password_reset";
+const DUMMY_SALT: &[u8] = b"halp-dummy-salt";
+
+// How many times argon2 has run on this thread (so tests can check that every login outcome costs
+// the same)
+#[cfg(test)]
+thread_local! {
+    pub static ARGON2_RUNS: std::cell::Cell<usize> = std::cell::Cell::default();
+}
 
-// Structures
-#[derive(Debug)]
-struct TestEntry {
-    id: i32,
-    text: String,
+fn count_argon2_run() {
+    #[cfg(test)]
+    ARGON2_RUNS.with(|runs| runs.set(runs.get() + 1));
 }
 
+// Structures
 struct User {
     unique_id: isize,
-    username: String,
-    email: String,
     password_hash: String,
-    registration_datetime: String,
+    locked_until: Option<String>,
+}
+
+// Who wrote a thread or comment, with their most senior role as a badge (students have none)
+#[derive(Serialize)]
+pub struct Author {
+    unique_id: isize,
+    username: String,
+    display_name: String,
+    badge: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct Thread {
     unique_id: isize,
     title: String,
-    creator_uid: isize,
+    author: Author,
     creation_timestamp: String,
-    tag: String,
+    tags: Vec<String>,
     content: String,
+    edited_at: Option<String>,
+    upvotes: i64,
+    comment_count: i64,
+    last_activity_at: String,
 }
 
 #[derive(Serialize)]
 pub struct Comment {
     unique_id: isize,
     thread_id: isize,
-    creator_uid: isize,
+    author: Author,
     creation_timestamp: String,
     content: String,
+    edited_at: Option<String>,
+    upvotes: i64,
 }
 
-// Testing and Debugging
-pub fn test_db() -> rusqlite::Result<()> {
-    let conn = Connection::open_in_memory()?;
+#[derive(Serialize)]
+pub struct Session {
+    session_id: isize,
+    key_prefix: Option<String>,
+    created_at: Option<String>,
+    last_used: Option<String>,
+    expiration: String,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+    current: bool,
+}
 
-    conn.execute(
-        "CREATE TABLE testing (id INTEGER PRIMARY KEY, text TEXT NOT NULL)",
-        []
-    )?;
+// The signed-in user, resolved once per request from their authentication key
+pub struct CurrentUser {
+    pub unique_id: String,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub roles: Vec<String>,
+    pub email_verified: bool,
+    pub authentication_key: String,
+}
 
-    let test_entry = TestEntry {
-        id: 0i32,
-        text: String::from("Testing"),
-    };
+impl CurrentUser {
+    // Whether any of the user's roles grant the permission
+    pub fn has_permission<P: Permission>(&self) -> bool {
+        self.roles.iter().any(|role| P::ROLES.contains(&role.as_str()))
+    }
+}
 
-    conn.execute(
-        "INSERT INTO testing (id, text) VALUES (?1, ?2)",
-        params![test_entry.id, test_entry.text],
-    )?;
+pub struct ClientDetails {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
 
-    let mut stmt = conn.prepare("SELECT id, text FROM testing")?;
+// A thread or comment matching a search, with the matches marked up in the title and snippet
+#[derive(Serialize)]
+pub struct SearchResult {
+    kind: String,
+    thread_id: isize,
+    comment_id: Option<isize>,
+    title: String,
+    tags: Vec<String>,
+    snippet: String,
+    score: f64,
+}
 
-    let row_iter = stmt.query_map([], |row| {
-        Ok(TestEntry {
-            id: row.get(0)?,
-            text: row.get(1)?,
-        })
-    })?;
+// A tag, with the number of threads that carry it
+#[derive(Serialize)]
+pub struct Tag {
+    name: String,
+    description: Option<String>,
+    color: Option<String>,
+    predefined: bool,
+    thread_count: i64,
+}
 
-    for entry in row_iter {
-        println!("Found row: {:?}", entry.unwrap());
-    }
+// Conditions a thread listing can be narrowed down by (all of the given ones must hold)
+pub struct ThreadFilters {
+    pub tag: Option<String>,
+    pub author: Option<String>,
+    pub created_before: Option<String>,
+    pub created_after: Option<String>,
+    pub has_comments: Option<bool>,
+}
 
-    Ok(())
+#[derive(Serialize)]
+pub struct Revision {
+    version: usize,
+    timestamp: String,
+    title: Option<String>,
+    tag: Option<String>,
+    content: String,
+    diff: Option<RevisionDiff>,
+}
+
+#[derive(Serialize)]
+pub struct RevisionDiff {
+    title: Option<Vec<String>>,
+    tag: Option<Vec<String>>,
+    content: Vec<String>,
 }
 
 // App Logic Functions
-pub fn connect_db(path: &String, in_memory: bool) -> rusqlite::Result<Connection> {
+pub fn connect_db(path: &String, in_memory: bool, busy_timeout: StdDuration) -> AppResult<Connection> {
     // Get path specified in argument as an actual path
     let db_path = Path::new(path);
 
-    // Open a connection to the database
+    // Open a connection to the database (in-memory databases are named and use a shared cache
+    // so that every connection opened with the same path sees the same data)
     let conn = match in_memory {
-        true => Connection::open_in_memory()?,
+        true => Connection::open_with_flags(
+            format!("file:{}?mode=memory&cache=shared", path),
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_CREATE
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_SHARED_CACHE
+        )?,
         false => Connection::open(db_path)?,
     };
 
+    // Wait on locks held by other connections instead of failing immediately
+    conn.busy_timeout(busy_timeout)?;
+
+    // Enforce foreign keys, and let readers and the writer work concurrently (file databases only)
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    if !in_memory {
+        conn.execute_batch("PRAGMA journal_mode = WAL;")?;
+    }
+
     // Return the connection
     Ok(conn)
 }
 
-pub fn setup_database(conn: &mut Connection) -> rusqlite::Result<()> {
+struct StoredKey {
+    unique_id: isize,
+    user_id: isize,
+    expiration: String,
+}
 
-    // Create Users table
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS users ( \
-                unique_id INTEGER PRIMARY KEY, \
-                username TEXT UNIQUE NOT NULL, \
-                email TEXT UNIQUE, \
-                password_hash TEXT NOT NULL, \
-                password_salt TEXT NOT NULL, \
-                registration_datetime TEXT \
-            );",
-        []
-    )?;
-
-    // Create Authentication Keys table
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS authentication_keys ( \
-                unique_id INTEGER PRIMARY KEY, \
-                user_id INTEGER NOT NULL, \
-                authentication_key TEXT NOT NULL, \
-                expiration TEXT NOT NULL, \
-                FOREIGN KEY (user_id) references users(unique_id) ON DELETE CASCADE \
-            );",
-        []
-    )?;
+fn find_key(conn: &mut Connection, auth_key: &str) -> AppResult<Option<StoredKey>> {
+    // Finds the stored authentication key matching a presented key (keys are stored as digests)
+    let digest = keys::hash_key(auth_key);
 
-    // Create the User Privileges table
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS user_privileges ( \
-                privilege_unique_id INTEGER PRIMARY KEY, \
-                user_id INTEGER NOT NULL, \
-                privilege TEXT NOT NULL, \
-                FOREIGN KEY (user_id) references users(unique_id) ON DELETE CASCADE \
-            );",
-        []
+    // Prepare query in the DB that retrieves all keys sharing the presented key's prefix
+    let mut auth_keys_query = conn.prepare("SELECT \
+                        unique_id, user_id, authentication_key, expiration \
+                      FROM \
+                        authentication_keys \
+                      WHERE \
+                        key_prefix = ?1"
     )?;
 
-    // Create the Threads/Posts table
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS threads ( \
-                unique_id INTEGER PRIMARY KEY, \
-                title TEXT NOT NULL, \
-                creator_uid INTEGER NOT NULL, \
-                creation_timestamp TEXT, \
-                tag TEXT, \
-                content TEXT NOT NULL, \
-                FOREIGN KEY (creator_uid) references users(unique_id) ON DELETE CASCADE \
-            );",
-        []
-    )?;
-
-    // Create Comments table
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS comments ( \
-                unique_id INTEGER PRIMARY KEY, \
-                thread_id INTEGER NOT NULL, \
-                creator_uid INTEGER NOT NULL, \
-                creation_timestamp TEXT, \
-                content TEXT NOT NULL, \
-                FOREIGN KEY (thread_id) references threads(unique_id) ON DELETE CASCADE, \
-                FOREIGN KEY (creator_uid) references users(unique_id) ON DELETE CASCADE \
-            );",
-        []
-    )?;
-
-    // Return success if everything completes
-    Ok(())
+    let auth_key_results = auth_keys_query.query_map(params![keys::key_prefix(auth_key)], |row| {
+        let stored_digest: String = row.get(2)?;
+        Ok((stored_digest, StoredKey {
+            unique_id: row.get(0)?,
+            user_id: row.get(1)?,
+            expiration: row.get(3)?,
+        }))
+    })?;
+
+    // Compare digests in constant time
+    for entry in auth_key_results {
+        let (stored_digest, stored_key) = entry?;
+        if keys::constant_time_eq(&stored_digest, &digest) {
+            return Ok(Some(stored_key));
+        }
+    }
+
+    // If nothing was found
+    Ok(None)
 }
 
-pub fn authenticate(conn: &mut Connection, auth_key: &String) -> rusqlite::Result<bool> {
-    // Verifies a username and authentication token against the database (and expiration datetime)
+pub fn current_user(conn: &mut Connection, auth_key: &str) -> AppResult<Option<CurrentUser>> {
+    // Verifies an authentication token against the database (and expiration datetime), and
+    // loads the user it belongs to
 
     // Get the current time
     let now = Utc::now();
-    println!("Time now is: {}", now.to_rfc3339());
 
-    // Prepare query in the DB that retrieves all matching authentication keys
-    let mut auth_keys_query = match conn.prepare("SELECT \
-                        expiration \
-                      FROM \
-                        authentication_keys \
-                      WHERE \
-                        authentication_key = ?1"
-    ) {
+    // Find the matching authentication key (no match means the key is invalid)
+    let stored_key = match find_key(conn, auth_key)? {
+        Some(val) => val,
+        None => return Ok(None),
+    };
+
+    // Parse the expiration datetime
+    let expiration_datetime = match DateTime::parse_from_rfc3339(stored_key.expiration.as_str()) {
         Ok(val) => val,
         Err(e) => {
-            println!("Error encountered while running query for Authenticate: {}", e);
-            panic!("Panicked after not being able to resolve error!")
+            println!("Error when parsing expiration datetime: {}", e);
+            return Err(AppError::Internal(String::from("Stored authentication key is malformed")));
         }
     };
 
-    // Iterate through results and verify that the authentication key has not expired
-    // Note: Also checks for lack of auth tokens by never running expiration checks if no matching
-    // rows exist
-    let mut valid_key_encountered = false;
-    let auth_key_results = auth_keys_query.query_map(params![auth_key], |row| {
-        // Get values from the row as String objects
-        let expiration: String = row.get(0)?;
-
-        // Parse the row values
-        let expiration_datetime = match DateTime::parse_from_rfc3339(expiration.as_str()) {
-            Ok(val) => val,
-            Err(e) => {
-                println!("Error when parsing expiration datetime: {}", e);
-                panic!("Panicked while parsing expiration datetime!");
-            }
-        };
+    // Check expiration datetime against current time
+    let duration_since_expiration = now.signed_duration_since(expiration_datetime);
+    if duration_since_expiration.num_seconds() >= 0 {
+        println!("Rejected an expired authentication key!");
+        return Ok(None);
+    }
 
-        // Check expiration datetime against current time, set flag if still valid
-        let duration_since_expiration = now.signed_duration_since(expiration_datetime);
-        let mut not_expired_flag = false;
-        if duration_since_expiration.num_seconds() < 0 {
-            valid_key_encountered = true;
-            not_expired_flag = true;
-        }
+    // Record when the key was last used
+    conn.execute(
+        "UPDATE authentication_keys SET last_used = ?1 WHERE unique_id = ?2",
+        params![now.to_rfc3339(), stored_key.unique_id]
+    )?;
 
-        Ok(not_expired_flag)
-    })?;
+    // Load the user the key belongs to
+    let unique_user_id = stored_key.user_id.to_string();
+    let (username, display_name, email_verified): (String, Option<String>, bool) = conn.query_row(
+        "SELECT username, display_name, email_verified FROM users WHERE unique_id = ?1",
+        params![unique_user_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    )?;
+    let roles = get_user_roles(conn, &unique_user_id)?;
+
+    Ok(Some(CurrentUser {
+        unique_id: unique_user_id,
+        username,
+        display_name,
+        roles,
+        email_verified,
+        authentication_key: auth_key.to_string(),
+    }))
+}
 
-    // Actually run iterator (and count how many expired authentication keys have been collected)
-    let mut expired_tokens = 0;
-    for entry in auth_key_results {
-        let safe_entry = entry?;
-        if safe_entry {
-            expired_tokens += 1;
-        }
+pub fn get_username_from_uid(conn: &mut Connection, unique_id: &String) -> AppResult<String> {
+    // Look up the username belonging to the user id
+    let username = conn.query_row(
+        "SELECT username FROM users WHERE unique_id = ?1",
+        params![unique_id],
+        |row| row.get::<_, String>(0),
+    ).optional()?;
+
+    match username {
+        Some(username) => Ok(username),
+        None => Err(AppError::NotFound(String::from("User not found"))),
     }
-    println!("Found {} expired token(s) while authenticating!", expired_tokens);
+}
 
-    // Return the result of the expiration check
-    Ok(valid_key_encountered)
+pub fn get_uid_from_username(conn: &mut Connection, username: &String) -> AppResult<String> {
+    // Look up the user id belonging to the username
+    let uid = conn.query_row(
+        "SELECT unique_id FROM users WHERE username = ?1",
+        params![username],
+        |row| row.get::<_, isize>(0),
+    ).optional()?;
+
+    match uid {
+        Some(uid) => Ok(uid.to_string()),
+        None => Err(AppError::NotFound(String::from("User not found"))),
+    }
 }
 
-pub fn reverse_key_lookup(conn: &mut Connection, auth_key: &String) -> rusqlite::Result<String> {
-    // Prepare query in the DB that retrieves all matching authentication keys
-    let mut reverse_key_lookup_query = match conn.prepare("SELECT \
-                        user_id \
-                      FROM \
-                        authentication_keys \
-                      WHERE \
-                        authentication_key = ?1"
-    ) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Error encountered while running query for reverse key lookup: {}", e);
-            panic!("Panicked after not being able to resolve error!")
-        }
-    };
+pub fn set_display_name(conn: &mut Connection, unique_user_id: &String, display_name: &str) -> AppResult<bool> {
+    // A blank display name goes back to showing the username
+    let display_name = display_name.trim();
+    if let Some(message) = validation::check_display_name(display_name) {
+        return Err(AppError::InvalidFields(vec![FieldError::new("display_name", &message)]));
+    }
 
-    // Iterate through results to find matching user_id
-    let reverse_key_lookup_results = reverse_key_lookup_query.query_map(params![auth_key], |row| {
-        // Get values from the row as String objects
-        let user_id: isize = row.get(0)?;
-        Ok(user_id.to_string())
-    })?;
+    conn.execute(
+        "UPDATE users SET display_name = ?1 WHERE unique_id = ?2",
+        params![if display_name.is_empty() { None } else { Some(display_name) }, unique_user_id]
+    )?;
+
+    // If all succeeds, return true
+    Ok(true)
+}
 
-    // Actually run iterator to find the matching user_id
-    for entry in reverse_key_lookup_results {
-        return Ok(entry?)
+pub fn check_length(field: &str, value: &str, max_length: usize) -> AppResult<()> {
+    // Rejects empty values and values longer than the configured limit
+    let length = value.chars().count();
+    if value.trim().is_empty() {
+        return Err(AppError::Validation(format!("{} must not be empty", field)));
+    }
+    if length > max_length {
+        return Err(AppError::Validation(format!("{} must be at most {} characters", field, max_length)));
     }
 
-    // If nothing was found
-    Err(rusqlite::Error::InvalidQuery)
+    Ok(())
 }
 
-pub fn get_username_from_uid(conn: &mut Connection, unique_id: &String) -> rusqlite::Result<String> {
-    // Prepare query in the DB that retrieves all matching authentication keys
-    let mut matching_usernames_query = match conn.prepare("SELECT \
-                        username \
-                      FROM \
-                        users \
-                      WHERE \
-                        unique_id = ?1"
-    ) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Error encountered while running query for user based on uid: {}", e);
-            panic!("Panicked after not being able to resolve error!")
-        }
-    };
+pub fn check_thread_limits(limits: &ContentConfig, title: Option<&str>, content: Option<&str>) -> AppResult<()> {
+    // Check whichever fields are present against the configured content limits
+    if let Some(title) = title {
+        check_length("title", title, limits.max_title_length)?;
+    }
+    if let Some(content) = content {
+        check_length("content", content, limits.max_content_length)?;
+    }
 
-    // Iterate through results to find matching username
-    let matching_username_results = matching_usernames_query.query_map(params![unique_id], |row| {
-        // Get values from the row as String objects
-        let username: String = row.get(0)?;
-        Ok(username)
-    })?;
+    Ok(())
+}
 
-    // Actually run iterator to find the matching user_id
-    for entry in matching_username_results {
-        return Ok(entry?)
+pub fn check_tags(limits: &ContentConfig, tags: &[String]) -> AppResult<Vec<String>> {
+    // Tags are stored by canonical name, so "HW1" and "hw1" are the same tag (and only count once)
+    let mut canonical_tags: Vec<String> = Vec::new();
+    let mut errors: Vec<FieldError> = Vec::new();
+    for tag in tags {
+        let tag = validation::normalize_tag(tag);
+        match validation::check_tag(&tag, limits.max_tag_length) {
+            Some(message) => errors.push(FieldError::new("tags", &message)),
+            None if !canonical_tags.contains(&tag) => canonical_tags.push(tag),
+            None => (),
+        }
     }
 
-    // If nothing was found
-    Err(rusqlite::Error::InvalidQuery)
+    if canonical_tags.is_empty() && errors.is_empty() {
+        errors.push(FieldError::new("tags", "At least one tag is required"));
+    }
+    if canonical_tags.len() > limits.max_tags_per_thread {
+        errors.push(FieldError::new("tags", &format!("Threads can have at most {} tags", limits.max_tags_per_thread)));
+    }
+    if !errors.is_empty() {
+        return Err(AppError::InvalidFields(errors));
+    }
+
+    Ok(canonical_tags)
 }
 
-pub fn get_uid_from_username(conn: &mut Connection, username: &String) -> rusqlite::Result<String> {
-    // Prepare query in the DB that retrieves all matching authentication keys
-    let mut matching_uids_query = match conn.prepare("SELECT \
-                        unique_id \
-                      FROM \
-                        users \
-                      WHERE \
-                        username = ?1"
-    ) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Error encountered while running query for username based on username: {}", e);
-            panic!("Panicked after not being able to resolve error!")
+pub fn check_tag_details(limits: &ContentConfig, name: Option<&String>, description: Option<&String>, color: Option<&String>) -> AppResult<Option<String>> {
+    // Check whichever fields are present, returning the canonical name (blank descriptions and
+    // colors are fine, they clear the field)
+    let mut errors: Vec<FieldError> = Vec::new();
+    let name = name.map(|name| validation::normalize_tag(name));
+    if let Some(message) = name.as_ref().and_then(|name| validation::check_tag(name, limits.max_tag_length)) {
+        errors.push(FieldError::new("name", &message));
+    }
+    if let Some(description) = description {
+        if description.chars().count() > limits.max_title_length {
+            errors.push(FieldError::new("description", &format!("Must be at most {} characters", limits.max_title_length)));
         }
+    }
+    if let Some(message) = color.filter(|color| !color.is_empty()).and_then(|color| validation::check_color(color)) {
+        errors.push(FieldError::new("color", message));
+    }
+
+    if !errors.is_empty() {
+        return Err(AppError::InvalidFields(errors));
+    }
+
+    Ok(name)
+}
+
+pub fn check_page_size(limits: &ContentConfig, limit: Option<usize>) -> AppResult<usize> {
+    // Listings default to the configured page size, and can't ask for more than the maximum
+    match limit {
+        None => Ok(limits.default_page_size),
+        Some(limit) if (1..=limits.max_page_size).contains(&limit) => Ok(limit),
+        Some(_) => Err(AppError::Validation(format!("limit must be between 1 and {}", limits.max_page_size))),
+    }
+}
+
+pub fn check_thread_filters(
+    tag: Option<String>,
+    author: Option<String>,
+    created_before: Option<String>,
+    created_after: Option<String>,
+    has_comments: Option<bool>,
+    unanswered: Option<bool>,
+) -> AppResult<ThreadFilters> {
+    // Blank values are the same as leaving the filter out
+    let present = |value: Option<String>| value.map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+
+    let mut errors: Vec<FieldError> = Vec::new();
+    let mut timestamp = |field: &str, value: Option<String>| match present(value) {
+        Some(value) => match parse_filter_timestamp(&value) {
+            Some(val) => Some(val),
+            None => {
+                errors.push(FieldError::new(field, "Must be a date (2021-03-01) or an RFC 3339 timestamp"));
+                None
+            }
+        },
+        None => None,
+    };
+    let created_before = timestamp("created_before", created_before);
+    let created_after = timestamp("created_after", created_after);
+
+    // `unanswered` is shorthand for threads without comments
+    let has_comments = match (has_comments, unanswered.map(|unanswered| !unanswered)) {
+        (Some(a), Some(b)) if a != b => {
+            errors.push(FieldError::new("unanswered", "Contradicts has_comments"));
+            None
+        },
+        (a, b) => a.or(b),
     };
 
-    // Iterate through results to find matching uid
-    let matching_uid_results = matching_uids_query.query_map(params![username], |row| {
-        // Get values from the row as String objects
-        let uid: isize = row.get(0)?;
-        Ok(uid.to_string())
-    })?;
+    if !errors.is_empty() {
+        return Err(AppError::InvalidFields(errors));
+    }
+
+    Ok(ThreadFilters {
+        tag: present(tag).map(|tag| validation::normalize_tag(&tag)),
+        author: present(author),
+        created_before,
+        created_after,
+        has_comments,
+    })
+}
 
-    // Actually run iterator to find the matching user_id
-    for entry in matching_uid_results {
-        return Ok(entry?)
+fn parse_filter_timestamp(value: &str) -> Option<String> {
+    // Timestamps are stored as UTC RFC 3339 strings, so bounds are normalized the same way to be
+    // compared as text (a bare date means midnight UTC)
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Some(timestamp.with_timezone(&Utc).to_rfc3339());
     }
+    match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(date) => date.and_hms_opt(0, 0, 0).map(|midnight| Utc.from_utc_datetime(&midnight).to_rfc3339()),
+        Err(_) => None,
+    }
+}
 
-    // If nothing was found
-    Err(rusqlite::Error::InvalidQuery)
+pub fn thread_exists(conn: &mut Connection, thread_uid: &String) -> AppResult<bool> {
+    // Count the threads matching the given ID
+    let count: isize = conn.query_row(
+        "SELECT COUNT(*) FROM threads WHERE unique_id = ?1",
+        params![thread_uid],
+        |row| row.get(0)
+    )?;
+
+    Ok(count > 0)
+}
+
+fn record_failed_login(conn: &mut Connection, unique_user_id: &String, lockout: &RateLimitConfig) -> AppResult<()> {
+    // Count the failed attempt against the user
+    let now = Utc::now();
+    conn.execute(
+        "UPDATE users SET failed_login_count = failed_login_count + 1, last_failed_login = ?1 WHERE unique_id = ?2",
+        params![now.to_rfc3339(), unique_user_id]
+    )?;
+
+    // Lock the account once too many attempts have failed, doubling the lockout with each further failure
+    let failed_login_count: i64 = conn.query_row(
+        "SELECT failed_login_count FROM users WHERE unique_id = ?1",
+        params![unique_user_id],
+        |row| row.get(0)
+    )?;
+    if failed_login_count >= lockout.lockout_threshold {
+        let doublings = (failed_login_count - lockout.lockout_threshold).min(20) as u32;
+        let lockout_secs = lockout.lockout_base_secs.saturating_mul(2i64.pow(doublings)).min(lockout.lockout_max_secs);
+        conn.execute(
+            "UPDATE users SET locked_until = ?1 WHERE unique_id = ?2",
+            params![(now + Duration::seconds(lockout_secs)).to_rfc3339(), unique_user_id]
+        )?;
+    }
+
+    Ok(())
+}
+
+fn is_locked_out(locked_until: &Option<String>) -> bool {
+    // Whether an account lockout is still in effect
+    match locked_until.as_ref().map(|val| DateTime::parse_from_rfc3339(val)) {
+        Some(Ok(locked_until)) => locked_until > Utc::now(),
+        _ => false,
+    }
+}
+
+pub fn clear_lockout(conn: &mut Connection, username: &String) -> AppResult<bool> {
+    // Reset the failed login counter and lift any lockout
+    let updated = conn.execute(
+        "UPDATE users SET failed_login_count = 0, locked_until = NULL WHERE username = ?1",
+        params![username]
+    )?;
+
+    if updated == 0 {
+        return Err(AppError::NotFound(String::from("User not found")));
+    }
+
+    // If all succeeds, return true
+    Ok(true)
 }
 
-pub fn login(conn: &mut Connection, username: &String, password: &String) -> rusqlite::Result<(String, String)> {
+pub fn login(conn: &mut Connection, username: &String, password: &String, config: &AppConfig, client: &ClientDetails) -> AppResult<(String, String)> {
     // Creates an authentication token for a user given the user's password
 
     // Create statement that finds the desired user
     let mut user_query_statement = conn.prepare(
         "SELECT \
-                unique_id, username, email, password_hash, registration_datetime \
+                unique_id, password_hash, locked_until \
              FROM \
                 users \
              WHERE \
@@ -336,190 +545,1366 @@ pub fn login(conn: &mut Connection, username: &String, password: &String) -> rus
     let row_iter = user_query_statement.query_map(params![username], |row| {
         Ok(User {
             unique_id: row.get(0)?,
-            username: row.get(1)?,
-            email: row.get(2)?,
-            password_hash: row.get(3)?,
-            registration_datetime: row.get(4)?
+            password_hash: row.get(1)?,
+            locked_until: row.get(2)?
         })
     })?;
 
     // Iterate through matching users and verify correct password
     let mut matching_uid: Option<String> = None;
+    let mut matching_hash = String::new();
+    let mut failed_uid: Option<String> = None;
+    let mut locked = false;
     for entry in row_iter {
         let user = entry?;
 
-        // Check if the password matches
-        let password_valid = match argon2::verify_encoded(&user.password_hash, (&password).as_ref()) {
-            Ok(val) => {
-                matching_uid = Some(user.unique_id.to_string());
-                val
-            },
-            Err(e) => {
-                println!("Encountered an error while attempting to validate password: {}", e);
-                false
-            }
-        };
+        // Check if the password matches (only a verified password identifies the user)
+        let password_valid = verify_password(&user.password_hash, password);
+
+        // Locked accounts can't log in until the lockout expires (whatever the password)
+        if is_locked_out(&user.locked_until) {
+            locked = true;
+        } else if password_valid {
+            matching_uid = Some(user.unique_id.to_string());
+            matching_hash = user.password_hash;
+        } else {
+            failed_uid = Some(user.unique_id.to_string());
+        }
     }
+    drop(user_query_statement);
 
-    // Create a new authentication key for the user
-    let authentication_key: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(32)
-        .map(char::from)
-        .collect();
-
-    // Generate a new expiration date for the new authentication key
-    let expiration_date = Utc::now() + Duration::days(10);
+    // Unknown users still pay for a hash so that they can't be told apart by timing (locked
+    // accounts already paid for theirs above)
+    if matching_uid.is_none() && failed_uid.is_none() && !locked {
+        count_argon2_run();
+        let _ = argon2::hash_encoded(password.as_ref(), DUMMY_SALT, &config.password_hashing.argon2_config());
+    }
 
-    // Record the authentication key and the expiration date in the DB
-    match matching_uid {
-        Some(unique_id) => {
-            conn.execute(
-                "INSERT INTO authentication_keys (user_id, authentication_key, expiration) VALUES (?1, ?2, ?3)",
-                params![unique_id, authentication_key, expiration_date.to_rfc3339()]
-            )?;
+    // Wrong passwords, locked accounts and unknown users get exactly the same response, so that
+    // none of them give away which usernames exist
+    if locked {
+        return Err(AppError::Unauthorized(String::from("Invalid username or password")));
+    }
+    let unique_id = match (matching_uid, failed_uid) {
+        (Some(unique_id), _) => unique_id,
+        (None, Some(unique_id)) => {
+            record_failed_login(conn, &unique_id, &config.rate_limit)?;
+            return Err(AppError::Unauthorized(String::from("Invalid username or password")));
         },
-        None => {
+        (None, None) => {
             println!("Tried to authenticate for a user that doesn't exist!");
-            return Err(rusqlite::Error::InvalidQuery);
+            return Err(AppError::Unauthorized(String::from("Invalid username or password")));
         }
+    };
+
+    // A successful login clears the failed login counter
+    conn.execute(
+        "UPDATE users SET failed_login_count = 0, locked_until = NULL WHERE unique_id = ?1",
+        params![unique_id]
+    )?;
+
+    // Upgrade hashes made with older or weaker parameters while the plaintext password is at hand
+    if needs_rehash(&matching_hash, &config.password_hashing) {
+        let upgraded_hash = hash_password(password, &config.password_hashing)?;
+        conn.execute(
+            "UPDATE users SET password_hash = ?1 WHERE unique_id = ?2",
+            params![upgraded_hash, unique_id]
+        )?;
     }
 
+    // Create a new authentication key for the user
+    let authentication_key = keys::generate_key(config.auth.key_length);
+
+    // Generate a new expiration date for the new authentication key
+    let now = Utc::now();
+    let expiration_date = now + Duration::hours(config.auth.token_ttl_hours);
+
+    // Record the authentication key and the expiration date in the DB
+    conn.execute(
+        "INSERT INTO \
+                authentication_keys (user_id, authentication_key, key_prefix, expiration, created_at, user_agent, ip_address) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            unique_id,
+            keys::hash_key(&authentication_key),
+            keys::key_prefix(&authentication_key),
+            expiration_date.to_rfc3339(),
+            now.to_rfc3339(),
+            client.user_agent,
+            client.ip_address
+        ]
+    )?;
+
     // Return authentication key and the expiration date
     Ok((authentication_key, expiration_date.to_rfc3339()))
 }
 
-pub fn get_threads(conn: &mut Connection) -> rusqlite::Result<Vec<Thread>> {
+fn issue_user_token(conn: &mut Connection, unique_user_id: &String, purpose: &str, ttl: Duration, key_length: usize) -> AppResult<String> {
+    // Only the most recently issued token for a purpose stays valid
+    conn.execute(
+        "DELETE FROM user_tokens WHERE user_id = ?1 AND purpose = ?2",
+        params![unique_user_id, purpose]
+    )?;
 
-    // Craft the SQL query
-    let mut threads_query_statement = conn.prepare(
-        "SELECT unique_id, title, creator_uid, creation_timestamp, tag, content FROM threads"
+    // Store the token's digest, never the token itself
+    let token = keys::generate_key(key_length);
+    let now = Utc::now();
+    conn.execute(
+        "INSERT INTO \
+                user_tokens (user_id, purpose, token_hash, created_at, expiration) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![unique_user_id, purpose, keys::hash_key(&token), now.to_rfc3339(), (now + ttl).to_rfc3339()]
     )?;
 
-    // Create iterator to iterate through matching DB rows
-    let row_iter = threads_query_statement.query_map([], |row| {
-        Ok(Thread {
-            unique_id: row.get(0)?,
-            title: row.get(1)?,
-            creator_uid: row.get(2)?,
-            creation_timestamp: row.get(3)?,
-            tag: row.get(4)?,
-            content: row.get(5)?,
-        })
-    })?;
+    Ok(token)
+}
 
-    // Vector to store thread structs in
-    let mut threads: Vec<Thread> = Vec::new();
+fn find_user_token(conn: &Connection, token: &str, purpose: &str) -> AppResult<(isize, String)> {
+    // Look the token up by its digest
+    let stored: Option<(isize, isize, String)> = conn.query_row(
+        "SELECT unique_id, user_id, expiration FROM user_tokens WHERE token_hash = ?1 AND purpose = ?2",
+        params![keys::hash_key(token), purpose],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    ).optional()?;
+    let (token_id, unique_user_id, expiration) = match stored {
+        Some(val) => val,
+        None => return Err(AppError::NotFound(String::from("The token is invalid or has already been used"))),
+    };
 
-    // Iterate through the DB rows
-    for entry in row_iter {
-        let thread = entry?;
-        threads.push(thread);
+    // Expired tokens are useless, so clear them out as they are found
+    let expired = match DateTime::parse_from_rfc3339(&expiration) {
+        Ok(val) => val < Utc::now(),
+        Err(_) => true,
+    };
+    if expired {
+        conn.execute("DELETE FROM user_tokens WHERE unique_id = ?1", params![token_id])?;
+        return Err(AppError::NotFound(String::from("The token has expired")));
     }
 
-    // Return the vector of Thread structs
-    Ok(threads)
+    Ok((token_id, unique_user_id.to_string()))
 }
 
-pub fn get_thread_comments(conn: &mut Connection, thread_uid: &String) -> rusqlite::Result<Vec<Comment>> {
-    // Craft the SQL query
-    let mut comments_query_statement = conn.prepare(
-        "SELECT unique_id, thread_id, creator_uid, creation_timestamp, content FROM comments WHERE thread_id = ?1"
-    )?;
-
-    // Create iterator to iterate through matching DB rows
-    let row_iter = comments_query_statement.query_map(params![thread_uid], |row| {
-        Ok(Comment {
-            unique_id: row.get(0)?,
-            thread_id: row.get(1)?,
-            creator_uid: row.get(2)?,
-            creation_timestamp: row.get(3)?,
-            content: row.get(4)?,
-        })
-    })?;
-
-    // Vector to store comment structs in
-    let mut comments: Vec<Comment> = Vec::new();
-
-    // Iterate through the DB rows
-    for entry in row_iter {
-        let thread = entry?;
-        comments.push(thread);
+fn consume_user_token(tx: &Transaction, token: &str, purpose: &str) -> AppResult<String> {
+    // Tokens are single-use, so only the request that actually deletes it gets to use it
+    let (token_id, unique_user_id) = find_user_token(tx, token, purpose)?;
+    let deleted = tx.execute("DELETE FROM user_tokens WHERE unique_id = ?1", params![token_id])?;
+    if deleted != 1 {
+        return Err(AppError::NotFound(String::from("The token is invalid or has already been used")));
     }
 
-    // Return the vector of Comment structs
-    Ok(comments)
+    Ok(unique_user_id)
 }
 
-pub fn create_user(conn: &mut Connection, username: &String, email: &String, password: &String) -> rusqlite::Result<bool> {
-    // Get current time (to be registration datetime)
-    let now = Utc::now();
-
-    // Generate the salt to use
-    let salt: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(10)
-        .map(char::from)
-        .collect();
-    let config = Config::default();
-
-    // Generate the password hash based on the password and the salt
-    let hash = argon2::hash_encoded(password.as_ref(), salt.as_str().as_ref(), &config).unwrap();
+pub fn send_email_verification(conn: &mut Connection, unique_user_id: &String, mailer: &dyn Mailer, config: &AppConfig) -> AppResult<bool> {
+    // Nothing to do for addresses that are already verified
+    let (username, email, verified): (String, String, bool) = conn.query_row(
+        "SELECT username, email, email_verified FROM users WHERE unique_id = ?1",
+        params![unique_user_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    )?;
+    if verified {
+        return Err(AppError::Conflict(String::from("Email address is already verified")));
+    }
 
-    // Create the user in the database
-    conn.execute(
-        "INSERT INTO \
-                users (username, email, password_hash, password_salt, registration_datetime) \
-             VALUES (?1, ?2, ?3, ?4, ?5)",
-        params![username.as_str(), email.as_str(), hash.as_str(), salt, now.to_rfc3339().as_str()]
+    // Issue a new verification token and email the link
+    let token = issue_user_token(
+        conn,
+        unique_user_id,
+        EMAIL_VERIFICATION_TOKEN,
+        Duration::hours(config.auth.verification_ttl_hours),
+        config.auth.key_length
     )?;
+    let link = format!("{}/verify-email/{}", config.mail.public_url.trim_end_matches('/'), token);
+    mailer.send(&Email {
+        to: email,
+        subject: String::from("Verify your email address"),
+        body: format!(
+            "Hi {},\n\nPlease verify your email address by opening this link:\n\n{}\n\nThe link expires in {} hours.\n",
+            username, link, config.auth.verification_ttl_hours
+        ),
+    })?;
 
     // If all succeeds, return true
     Ok(true)
 }
 
-pub fn create_thread(conn: &mut Connection, title: &String, username: &String, tag: &String, content: &String) -> rusqlite::Result<bool> {
-    // Get current time (to be the thread creation timestamp)
-    let now = Utc::now();
+pub fn verify_email(conn: &mut Connection, token: &str) -> AppResult<bool> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
 
-    // Get the matching UID that corresponds to the user
-    let unique_user_id = get_uid_from_username(conn, username)?;
+    // Consume the token and mark the user's address as verified
+    let unique_user_id = consume_user_token(&tx, token, EMAIL_VERIFICATION_TOKEN)?;
+    tx.execute("UPDATE users SET email_verified = 1 WHERE unique_id = ?1", params![unique_user_id])?;
 
-    // Create the user in the database
-    conn.execute(
-        "INSERT INTO \
-                threads (title, creator_uid, creation_timestamp, tag, content) \
-             VALUES (?1, ?2, ?3, ?4, ?5)",
-        params![title, unique_user_id, now.to_rfc3339(), tag, content]
-    )?;
+    tx.commit()?;
 
     // If all succeeds, return true
     Ok(true)
 }
 
-pub fn create_comment(conn: &mut Connection, thread_uid: &String, username: &String, content: &String) -> rusqlite::Result<bool> {
-    // Get current time (to be the thread creation timestamp)
-    let now = Utc::now();
+pub fn revoke_key(conn: &mut Connection, auth_key: &str) -> AppResult<bool> {
+    // Delete the presented authentication key
+    if let Some(stored_key) = find_key(conn, auth_key)? {
+        conn.execute("DELETE FROM authentication_keys WHERE unique_id = ?1", params![stored_key.unique_id])?;
+    }
 
-    // Get the matching UID that corresponds to the user
-    let unique_user_id = get_uid_from_username(conn, username)?;
+    // If all succeeds, return true
+    Ok(true)
+}
 
-    // Create the user in the database
-    conn.execute(
-        "INSERT INTO \
-                comments (thread_id, creator_uid, creation_timestamp, content) \
-             VALUES (?1, ?2, ?3, ?4)",
-        params![thread_uid, unique_user_id, now.to_rfc3339(), content]
+pub fn revoke_all_keys(conn: &mut Connection, unique_user_id: &String) -> AppResult<usize> {
+    // Delete every authentication key belonging to the user
+    let revoked = conn.execute("DELETE FROM authentication_keys WHERE user_id = ?1", params![unique_user_id])?;
+
+    // Return how many keys were revoked
+    Ok(revoked)
+}
+
+pub fn revoke_session(conn: &mut Connection, unique_user_id: &String, session_id: &String) -> AppResult<bool> {
+    // Delete the session, but only if it belongs to the user
+    let revoked = conn.execute(
+        "DELETE FROM authentication_keys WHERE unique_id = ?1 AND user_id = ?2",
+        params![session_id, unique_user_id]
     )?;
 
+    if revoked == 0 {
+        return Err(AppError::NotFound(String::from("Session not found")));
+    }
+
     // If all succeeds, return true
     Ok(true)
 }
 
-pub fn delete_thread(conn: &mut Connection, thread_uid: &String) -> rusqlite::Result<bool> {
-    todo!("Implement delete_thread function")
-}
+fn set_password(conn: &mut Connection, unique_user_id: &String, new_password: &str, hashing_config: &HashingConfig) -> AppResult<()> {
+    // Rehash with a fresh salt
+    let hash = hash_password(new_password, hashing_config)?;
+    conn.execute(
+        "UPDATE users SET password_hash = ?1 WHERE unique_id = ?2",
+        params![hash, unique_user_id]
+    )?;
+
+    Ok(())
+}
+
+fn check_new_password(conn: &mut Connection, unique_user_id: &String, new_password: &str, config: &AppConfig) -> AppResult<()> {
+    // Apply the same password policy as registration
+    let username = get_username_from_uid(conn, unique_user_id)?;
+    match validation::check_password(new_password, &username, &config.password_policy) {
+        Some(message) => Err(AppError::InvalidFields(vec![FieldError::new("new_password", &message)])),
+        None => Ok(()),
+    }
+}
+
+pub fn change_password(conn: &mut Connection, unique_user_id: &String, current_password: &str, new_password: &str, current_key: &str, config: &AppConfig) -> AppResult<usize> {
+    // The current password must be confirmed before it can be replaced
+    let password_hash: String = conn.query_row(
+        "SELECT password_hash FROM users WHERE unique_id = ?1",
+        params![unique_user_id],
+        |row| row.get(0)
+    )?;
+    if !verify_password(&password_hash, current_password) {
+        return Err(AppError::InvalidFields(vec![FieldError::new("current_password", "Current password is incorrect")]));
+    }
+    check_new_password(conn, unique_user_id, new_password, config)?;
+
+    // Store the new password
+    set_password(conn, unique_user_id, new_password, &config.password_hashing)?;
+
+    // Sign out every other session (the one making the change stays signed in)
+    let current_session = match find_key(conn, current_key)? {
+        Some(stored_key) => stored_key.unique_id,
+        None => return Err(AppError::Unauthorized(String::from("Authentication key is not recognized"))),
+    };
+    let revoked = conn.execute(
+        "DELETE FROM authentication_keys WHERE user_id = ?1 AND unique_id != ?2",
+        params![unique_user_id, current_session]
+    )?;
+
+    // Return how many sessions were revoked
+    Ok(revoked)
+}
+
+pub fn request_password_reset(conn: &mut Connection, email: &String, mailer: &dyn Mailer, config: &AppConfig) -> AppResult<bool> {
+    // Find the account registered with the address
+    let user: Option<(isize, String, String)> = conn.query_row(
+        "SELECT unique_id, username, email FROM users WHERE email = ?1 COLLATE NOCASE",
+        params![email],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    ).optional()?;
+
+    // Unknown addresses get the same response, so that accounts can't be discovered this way (the
+    // email below is only queued, so known addresses don't take noticeably longer, and a failure to
+    // send it is logged rather than returned)
+    let (unique_user_id, username, email) = match user {
+        Some(val) => val,
+        None => return Ok(true),
+    };
+
+    // Issue a reset token and email it
+    let token = issue_user_token(
+        conn,
+        &unique_user_id.to_string(),
+        PASSWORD_RESET_TOKEN,
+        Duration::minutes(config.auth.password_reset_ttl_minutes),
+        config.auth.key_length
+    )?;
+    let send_result = mailer.send(&Email {
+        to: email,
+        subject: String::from("Reset your password"),
+        body: format!(
+            "Hi {},\n\nSomeone asked to reset the password for your account. If it was you, use this reset token:\n\n{}\n\nThe token expires in {} minutes. If you didn't ask for a reset, you can ignore this email.\n",
+            username, token, config.auth.password_reset_ttl_minutes
+        ),
+    });
+    if let Err(e) = send_result {
+        rocket::warn!("Unable to send a password reset email: {}", e);
+    }
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+pub fn reset_password(conn: &mut Connection, token: &str, new_password: &str, config: &AppConfig) -> AppResult<bool> {
+    // Check the token and the new password before using the token up (hashing happens outside the
+    // transaction, so that it doesn't hold up other writers)
+    let (_, unique_user_id) = find_user_token(conn, token, PASSWORD_RESET_TOKEN)?;
+    check_new_password(conn, &unique_user_id, new_password, config)?;
+    let hash = hash_password(new_password, &config.password_hashing)?;
+
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    // The token is single-use, so a concurrent reset with the same token fails here
+    if consume_user_token(&tx, token, PASSWORD_RESET_TOKEN)? != unique_user_id {
+        return Err(AppError::NotFound(String::from("The token is invalid or has already been used")));
+    }
+
+    // Store the new password, and lift any lockout (receiving the token also proves the email address)
+    tx.execute(
+        "UPDATE users SET \
+                password_hash = ?1, failed_login_count = 0, locked_until = NULL, email_verified = 1 \
+             WHERE unique_id = ?2",
+        params![hash, unique_user_id]
+    )?;
+
+    // Every existing session is signed out
+    tx.execute("DELETE FROM authentication_keys WHERE user_id = ?1", params![unique_user_id])?;
+
+    tx.commit()?;
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+pub fn get_sessions(conn: &mut Connection, unique_user_id: &String, current_key: &str) -> AppResult<Vec<Session>> {
+    // Get current time (only unexpired keys are active sessions)
+    let now = Utc::now();
+
+    // Work out which session the current key belongs to
+    let current_session_id = find_key(conn, current_key)?.map(|stored_key| stored_key.unique_id);
+
+    // Craft the SQL query
+    let mut sessions_query_statement = conn.prepare(
+        "SELECT unique_id, key_prefix, expiration, created_at, last_used, user_agent, ip_address \
+             FROM authentication_keys \
+             WHERE user_id = ?1 \
+             ORDER BY unique_id ASC"
+    )?;
+
+    // Create iterator to iterate through matching DB rows
+    let row_iter = sessions_query_statement.query_map(params![unique_user_id], |row| {
+        let session_id: isize = row.get(0)?;
+        Ok(Session {
+            session_id,
+            key_prefix: row.get(1)?,
+            expiration: row.get(2)?,
+            created_at: row.get(3)?,
+            last_used: row.get(4)?,
+            user_agent: row.get(5)?,
+            ip_address: row.get(6)?,
+            current: Some(session_id) == current_session_id,
+        })
+    })?;
+
+    // Vector to store session structs in
+    let mut sessions: Vec<Session> = Vec::new();
+
+    // Iterate through the DB rows, skipping expired keys
+    for entry in row_iter {
+        let session = entry?;
+        match DateTime::parse_from_rfc3339(session.expiration.as_str()) {
+            Ok(expiration) if now.signed_duration_since(expiration).num_seconds() < 0 => sessions.push(session),
+            _ => (),
+        }
+    }
+
+    // Return the vector of Session structs
+    Ok(sessions)
+}
+
+// Builds the SQL (and its parameters) for a page of threads (one extra row tells whether there
+// is another page)
+pub fn threads_query(filters: &ThreadFilters, sort: SortOrder, limit: usize, cursor: Option<&String>) -> AppResult<(String, Vec<Value>)> {
+    // Start after the cursor position, if there is one (its values are always ?1 and ?2)
+    let mut conditions: Vec<String> = Vec::new();
+    let mut query_params: Vec<Value> = Vec::new();
+    if let Some(cursor) = cursor {
+        let cursor = Cursor::decode(cursor, sort)?;
+        conditions.push(sort.after_cursor());
+        query_params.push(cursor.key);
+        query_params.push(Value::Integer(cursor.unique_id));
+    }
+
+    // Add a condition for each filter, with its value bound as the next parameter (user input
+    // never becomes part of the SQL itself)
+    let mut add_condition = |condition: &str, value: Value| {
+        query_params.push(value);
+        conditions.push(condition.replace('?', &format!("?{}", query_params.len())));
+    };
+    if let Some(tag) = &filters.tag {
+        // (The unary + keeps SQLite from fetching the tag's threads by rowid and sorting them all;
+        // instead it walks the sort order's index and checks each thread against the tag's list)
+        add_condition(
+            "+unique_id IN (SELECT thread_tags.thread_id FROM thread_tags JOIN tags ON tags.unique_id = thread_tags.tag_id WHERE tags.name = ?)",
+            Value::Text(tag.clone())
+        );
+    }
+    if let Some(author) = &filters.author {
+        add_condition("creator_uid = (SELECT unique_id FROM users WHERE username = ? COLLATE NOCASE)", Value::Text(author.clone()));
+    }
+    if let Some(created_before) = &filters.created_before {
+        add_condition("creation_timestamp < ?", Value::Text(created_before.clone()));
+    }
+    if let Some(created_after) = &filters.created_after {
+        add_condition("creation_timestamp >= ?", Value::Text(created_after.clone()));
+    }
+    match filters.has_comments {
+        Some(true) => conditions.push(String::from("EXISTS (SELECT 1 FROM comments WHERE comments.thread_id = threads.unique_id)")),
+        Some(false) => conditions.push(String::from("NOT EXISTS (SELECT 1 FROM comments WHERE comments.thread_id = threads.unique_id)")),
+        None => (),
+    }
+    let where_clause = match conditions.is_empty() {
+        true => String::new(),
+        false => format!("WHERE {}", conditions.join(" AND ")),
+    };
+
+    let sql = format!(
+        "SELECT \
+                unique_id, title, creation_timestamp, tag, content, edited_at, upvotes, last_activity_at, \
+                (SELECT COUNT(*) FROM comments WHERE comments.thread_id = threads.unique_id), {}, {} \
+             FROM threads {} \
+             {} ORDER BY {} LIMIT {}",
+        AUTHOR_COLUMNS, sort.column(), AUTHORS_JOIN, where_clause, sort.order_by(), limit + 1
+    );
+
+    Ok((sql, query_params))
+}
+
+pub fn get_threads(conn: &mut Connection, filters: &ThreadFilters, sort: SortOrder, limit: usize, cursor: Option<&String>) -> AppResult<Page<Thread>> {
+    // Craft the SQL query
+    let (sql, query_params) = threads_query(filters, sort, limit, cursor)?;
+    let mut threads_query_statement = conn.prepare(&sql)?;
+
+    // Create iterator to iterate through matching DB rows
+    let row_iter = threads_query_statement.query_map(params_from_iter(query_params), |row| {
+        let thread = Thread {
+            unique_id: row.get(0)?,
+            title: row.get(1)?,
+            creation_timestamp: row.get(2)?,
+            tags: split_tags(row.get(3)?),
+            content: row.get(4)?,
+            edited_at: row.get(5)?,
+            upvotes: row.get(6)?,
+            last_activity_at: row.get(7)?,
+            comment_count: row.get(8)?,
+            author: author_from_row(row, 9)?,
+        };
+        let sort_key: Value = row.get(13)?;
+        Ok((thread, sort_key))
+    })?;
+
+    // Vector to store thread structs in
+    let mut threads: Vec<(Thread, Value)> = Vec::new();
+
+    // Iterate through the DB rows
+    for entry in row_iter {
+        threads.push(entry?);
+    }
+
+    // Return the page of Thread structs
+    Ok(into_page(threads, sort, limit, |thread| thread.unique_id))
+}
+
+// Builds the SQL (and its parameters) for a page of a thread's comments (one extra row tells
+// whether there is another page)
+pub fn comments_query(thread_uid: &str, sort: SortOrder, limit: usize, cursor: Option<&String>) -> AppResult<(String, Vec<Value>)> {
+    // Start after the cursor position, if there is one
+    let mut query_params: Vec<Value> = Vec::new();
+    let cursor_condition = match cursor {
+        Some(cursor) => {
+            let cursor = Cursor::decode(cursor, sort)?;
+            query_params.push(cursor.key);
+            query_params.push(Value::Integer(cursor.unique_id));
+            format!("AND {}", sort.after_cursor())
+        },
+        None => {
+            // (?1 and ?2 go unused)
+            query_params.push(Value::Null);
+            query_params.push(Value::Null);
+            String::new()
+        },
+    };
+    query_params.push(Value::Text(thread_uid.to_string()));
+
+    let sql = format!(
+        "SELECT unique_id, thread_id, creation_timestamp, content, edited_at, upvotes, {}, {} FROM comments {} \
+             WHERE thread_id = ?3 {} ORDER BY {} LIMIT {}",
+        AUTHOR_COLUMNS, sort.column(), AUTHORS_JOIN, cursor_condition, sort.order_by(), limit + 1
+    );
+
+    Ok((sql, query_params))
+}
+
+pub fn get_thread_comments(conn: &mut Connection, thread_uid: &String, sort: SortOrder, limit: usize, cursor: Option<&String>) -> AppResult<Page<Comment>> {
+    // Make sure the thread actually exists
+    if !thread_exists(conn, thread_uid)? {
+        return Err(AppError::NotFound(String::from("Thread not found")));
+    }
+
+    // Craft the SQL query
+    let (sql, query_params) = comments_query(thread_uid, sort, limit, cursor)?;
+    let mut comments_query_statement = conn.prepare(&sql)?;
+
+    // Create iterator to iterate through matching DB rows
+    let row_iter = comments_query_statement.query_map(params_from_iter(query_params), |row| {
+        let comment = Comment {
+            unique_id: row.get(0)?,
+            thread_id: row.get(1)?,
+            creation_timestamp: row.get(2)?,
+            content: row.get(3)?,
+            edited_at: row.get(4)?,
+            upvotes: row.get(5)?,
+            author: author_from_row(row, 6)?,
+        };
+        let sort_key: Value = row.get(10)?;
+        Ok((comment, sort_key))
+    })?;
+
+    // Vector to store comment structs in
+    let mut comments: Vec<(Comment, Value)> = Vec::new();
+
+    // Iterate through the DB rows
+    for entry in row_iter {
+        comments.push(entry?);
+    }
+
+    // Return the page of Comment structs
+    Ok(into_page(comments, sort, limit, |comment| comment.unique_id))
+}
+
+// Joined into thread & comment listings to describe each author (the columns are renamed, so they
+// can't clash with the content's own). SQLite flattens this into a plain join on users, so the
+// listing can still be read in index order.
+const AUTHORS_JOIN: &str = "JOIN (\
+        SELECT \
+            unique_id AS author_uid, username AS author_username, \
+            COALESCE(display_name, username) AS author_display_name \
+        FROM users\
+    ) AS authors ON authors.author_uid = creator_uid";
+
+// Selected from the listing itself, so that only the rows on the page look up their author's badge.
+// Badges go to the most senior role: admin, instructor, then TA (picked with MIN rather than
+// ORDER BY ... LIMIT 1, which would sort every author's roles).
+const AUTHOR_COLUMNS: &str = "author_uid, author_username, author_display_name, \
+    (SELECT \
+         CASE MIN(CASE privilege WHEN 'admin' THEN 0 WHEN 'instructor' THEN 1 WHEN 'ta' THEN 2 END) \
+             WHEN 0 THEN 'admin' WHEN 1 THEN 'instructor' WHEN 2 THEN 'ta' END \
+     FROM user_privileges WHERE user_privileges.user_id = creator_uid)";
+
+// Reads the AUTHOR_COLUMNS, starting at the given column
+fn author_from_row(row: &Row, first: usize) -> rusqlite::Result<Author> {
+    Ok(Author {
+        unique_id: row.get(first)?,
+        username: row.get(first + 1)?,
+        display_name: row.get(first + 2)?,
+        badge: row.get(first + 3)?,
+    })
+}
+
+fn into_page<T>(mut rows: Vec<(T, Value)>, sort: SortOrder, limit: usize, unique_id: fn(&T) -> isize) -> Page<T> {
+    // Rows past the limit only signal that another page follows the last row shown
+    let next_cursor = match rows.len() > limit {
+        true => {
+            rows.truncate(limit);
+            rows.last().map(|(item, key)| Cursor { key: key.clone(), unique_id: unique_id(item) as i64 }.encode(sort))
+        },
+        false => None,
+    };
+
+    Page {
+        items: rows.into_iter().map(|(item, _)| item).collect(),
+        next_cursor,
+    }
+}
+
+pub fn search_content(conn: &mut Connection, query: &str, tag: Option<&String>, limit: usize) -> AppResult<Vec<SearchResult>> {
+    // Rebuild the query from the syntax we support
+    let fts_query = match search::fts_query(query) {
+        Some(val) => val,
+        None => return Err(AppError::Validation(String::from("q must contain at least one word to search for"))),
+    };
+
+    // Craft the SQL query (best matches first; title matches count the most, then tags, then content)
+    let tag_condition = match tag.is_some() {
+        true => "AND threads.unique_id IN \
+                     (SELECT thread_tags.thread_id FROM thread_tags JOIN tags ON tags.unique_id = thread_tags.tag_id WHERE tags.name = ?2)",
+        false => "",
+    };
+    let mut search_query_statement = conn.prepare(&format!(
+        "SELECT \
+                search_index.kind, search_index.thread_id, search_index.target_id, threads.title, threads.tag, \
+                highlight(search_index, 0, ?3, ?4), snippet(search_index, 2, ?3, ?4, '…', 24), \
+                bm25(search_index, 10.0, 5.0, 1.0) AS score \
+             FROM search_index JOIN threads ON threads.unique_id = search_index.thread_id \
+             WHERE search_index MATCH ?1 {} \
+             ORDER BY score LIMIT {}",
+        tag_condition, limit
+    ))?;
+
+    // Create iterator to iterate through matching DB rows
+    let tag = tag.map(|tag| validation::normalize_tag(tag)).unwrap_or_default();
+    let row_iter = search_query_statement.query_map(params![fts_query, tag, search::MATCH_START, search::MATCH_END], |row| {
+        let kind: String = row.get(0)?;
+        let target_id: isize = row.get(2)?;
+        let thread_title: String = row.get(3)?;
+        let highlighted_title: String = row.get(5)?;
+        let snippet: String = row.get(6)?;
+        let score: f64 = row.get(7)?;
+
+        // Comments show their thread's title (which didn't take part in the match, and comes
+        // straight from the threads table, so it has nothing to highlight)
+        let is_comment = kind == "comment";
+        let title = if is_comment { search::escape_html(&thread_title) } else { search::highlight_html(&highlighted_title) };
+        Ok(SearchResult {
+            kind,
+            thread_id: row.get(1)?,
+            comment_id: if is_comment { Some(target_id) } else { None },
+            title,
+            tags: split_tags(row.get(4)?),
+            snippet: search::highlight_html(&snippet),
+            // bm25 scores are negative, with the best match lowest
+            score: -score,
+        })
+    })?;
+
+    // Vector to store search results in
+    let mut results: Vec<SearchResult> = Vec::new();
+
+    // Iterate through the DB rows
+    for entry in row_iter {
+        results.push(entry?);
+    }
+
+    // Return the vector of SearchResult structs
+    Ok(results)
+}
+
+fn hash_password(password: &str, hashing_config: &HashingConfig) -> AppResult<String> {
+    // Generate a random salt (the encoded hash carries it, so it isn't stored separately)
+    let mut salt = vec![0u8; hashing_config.salt_length];
+    rand::thread_rng().fill(&mut salt[..]);
+    let config = hashing_config.argon2_config();
+
+    // Generate the password hash based on the password and the salt
+    let hash = match argon2::hash_encoded(password.as_ref(), &salt, &config) {
+        Ok(val) => val,
+        Err(e) => {
+            println!("Encountered an error while hashing a password: {}", e);
+            return Err(AppError::Internal(String::from("Unable to hash password")));
+        }
+    };
+
+    // Return the encoded hash
+    Ok(hash)
+}
+
+fn needs_rehash(password_hash: &str, hashing_config: &HashingConfig) -> bool {
+    // Encoded hashes look like $argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash> (hashes made before
+    // argon2 1.3 have no v= part)
+    let parts: Vec<&str> = password_hash.split('$').skip(1).collect();
+    let (variant, version, parameters, salt, hash) = match parts.as_slice() {
+        [variant, version, parameters, salt, hash] => (*variant, Some(*version), *parameters, *salt, *hash),
+        [variant, parameters, salt, hash] => (*variant, None, *parameters, *salt, *hash),
+        _ => return true,
+    };
+
+    // Anything other than the configured variant at the current version is replaced
+    if Variant::from_str(variant).ok() != Variant::from_str(&hashing_config.variant).ok() || version != Some("v=19") {
+        return true;
+    }
+
+    // As is anything hashed with weaker parameters than the configured ones
+    for parameter in parameters.split(',') {
+        let (name, value) = match parameter.split_once('=') {
+            Some((name, value)) => (name, value.parse::<u32>().unwrap_or(0)),
+            None => return true,
+        };
+        let weaker = match name {
+            "m" => value < hashing_config.mem_cost,
+            "t" => value < hashing_config.time_cost,
+            "p" => value < hashing_config.lanes,
+            _ => false,
+        };
+        if weaker {
+            return true;
+        }
+    }
+    let decoded_length = |value: &str| base64::decode_config(value, base64::STANDARD_NO_PAD).map(|bytes| bytes.len()).unwrap_or(0);
+    decoded_length(salt) < hashing_config.salt_length || decoded_length(hash) < hashing_config.hash_length as usize
+}
+
+fn verify_password(password_hash: &str, password: &str) -> bool {
+    // Check a password against its stored hash (treating unreadable hashes as a mismatch)
+    count_argon2_run();
+    match argon2::verify_encoded(password_hash, password.as_ref()) {
+        Ok(val) => val,
+        Err(e) => {
+            println!("Encountered an error while attempting to validate password: {}", e);
+            false
+        }
+    }
+}
+
+pub fn create_user(conn: &mut Connection, username: &str, email: &str, password: &str, hashing_config: &HashingConfig) -> AppResult<bool> {
+    // Get current time (to be registration datetime)
+    let now = Utc::now();
+
+    // Hash the password
+    let hash = hash_password(password, hashing_config)?;
+
+    // Take the write lock up front so the uniqueness check and the insert can't interleave with another registration
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    // Usernames and emails must be unique regardless of case
+    let mut conflicts: Vec<FieldError> = Vec::new();
+    let username_taken: isize = tx.query_row(
+        "SELECT COUNT(*) FROM users WHERE username = ?1 COLLATE NOCASE",
+        params![username],
+        |row| row.get(0)
+    )?;
+    if username_taken > 0 {
+        conflicts.push(FieldError::new("username", "Username is already taken"));
+    }
+    let email_taken: isize = tx.query_row(
+        "SELECT COUNT(*) FROM users WHERE email = ?1 COLLATE NOCASE",
+        params![email],
+        |row| row.get(0)
+    )?;
+    if email_taken > 0 {
+        conflicts.push(FieldError::new("email", "Email is already registered"));
+    }
+    if !conflicts.is_empty() {
+        return Err(AppError::ConflictingFields(conflicts));
+    }
+
+    // Create the user in the database
+    tx.execute(
+        "INSERT INTO \
+                users (username, email, password_hash, registration_datetime) \
+             VALUES (?1, ?2, ?3, ?4)",
+        params![username, email, hash.as_str(), now.to_rfc3339().as_str()]
+    )?;
+    tx.commit()?;
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+pub fn create_thread(conn: &mut Connection, title: &String, unique_user_id: &String, tags: &[String], content: &String, restrict_tags: bool) -> AppResult<bool> {
+    // Get current time (to be the thread creation timestamp)
+    let now = Utc::now();
+
+    // Create the thread in the database, along with its tags
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO \
+                threads (title, creator_uid, creation_timestamp, tag, content, last_activity_at) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?3)",
+        params![title, unique_user_id, now.to_rfc3339(), tags.join(" "), content]
+    )?;
+    set_thread_tags(&tx, &tx.last_insert_rowid().to_string(), tags, restrict_tags)?;
+    tx.commit()?;
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+fn set_thread_tags(conn: &Connection, thread_uid: &String, tags: &[String], restrict_tags: bool) -> AppResult<()> {
+    // Find each tag, creating the ones that don't exist yet (unless only predefined tags are allowed)
+    let mut tag_ids: Vec<isize> = Vec::new();
+    let mut errors: Vec<FieldError> = Vec::new();
+    for tag in tags {
+        let existing: Option<(isize, bool)> = conn.query_row(
+            "SELECT unique_id, predefined FROM tags WHERE name = ?1",
+            params![tag],
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).optional()?;
+        match existing {
+            Some((unique_id, predefined)) if predefined || !restrict_tags => tag_ids.push(unique_id),
+            _ if restrict_tags => errors.push(FieldError::new("tags", &format!("'{}' is not one of the course's tags", tag))),
+            _ => {
+                conn.execute("INSERT INTO tags (name, created_at) VALUES (?1, ?2)", params![tag, Utc::now().to_rfc3339()])?;
+                tag_ids.push(conn.last_insert_rowid() as isize);
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(AppError::InvalidFields(errors));
+    }
+
+    // Replace whatever tags the thread had
+    conn.execute("DELETE FROM thread_tags WHERE thread_id = ?1", params![thread_uid])?;
+    for tag_id in &tag_ids {
+        conn.execute("INSERT INTO thread_tags (thread_id, tag_id) VALUES (?1, ?2)", params![thread_uid, tag_id])?;
+    }
+
+    Ok(())
+}
+
+// threads.tag holds the thread's canonical tag names, separated by spaces
+fn split_tags(tags: String) -> Vec<String> {
+    tags.split_whitespace().map(String::from).collect()
+}
+
+pub fn create_comment(conn: &mut Connection, thread_uid: &String, unique_user_id: &String, content: &String) -> AppResult<bool> {
+    // Get current time (to be the thread creation timestamp)
+    let now = Utc::now();
+
+    // Make sure the thread being commented on actually exists
+    if !thread_exists(conn, thread_uid)? {
+        return Err(AppError::NotFound(String::from("Thread not found")));
+    }
+
+    // Create the comment in the database, bumping the thread's last activity
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO \
+                comments (thread_id, creator_uid, creation_timestamp, content) \
+             VALUES (?1, ?2, ?3, ?4)",
+        params![thread_uid, unique_user_id, now.to_rfc3339(), content]
+    )?;
+    tx.execute(
+        "UPDATE threads SET last_activity_at = ?1 WHERE unique_id = ?2",
+        params![now.to_rfc3339(), thread_uid]
+    )?;
+    tx.commit()?;
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+pub fn is_email_verified(conn: &mut Connection, unique_user_id: &String) -> AppResult<bool> {
+    // Whether the user has followed a verification (or password reset) link
+    let verified: bool = conn.query_row(
+        "SELECT email_verified FROM users WHERE unique_id = ?1",
+        params![unique_user_id],
+        |row| row.get(0)
+    )?;
+
+    Ok(verified)
+}
+
+pub fn get_user_roles(conn: &mut Connection, unique_user_id: &String) -> AppResult<Vec<String>> {
+    // Every user is a student, on top of whatever roles they have been granted
+    let mut roles: Vec<String> = vec![String::from(privileges::STUDENT)];
+
+    let mut roles_query = conn.prepare("SELECT privilege FROM user_privileges WHERE user_id = ?1 ORDER BY privilege")?;
+    let role_iter = roles_query.query_map(params![unique_user_id], |row| row.get(0))?;
+    for entry in role_iter {
+        roles.push(entry?);
+    }
+
+    // Return the roles
+    Ok(roles)
+}
+
+fn check_grantable_role(role: &str) -> AppResult<()> {
+    // Only the elevated roles are stored
+    if privileges::GRANTABLE_ROLES.contains(&role) {
+        Ok(())
+    } else {
+        Err(AppError::Validation(format!("'{}' is not one of {}", role, privileges::GRANTABLE_ROLES.join(", "))))
+    }
+}
+
+pub fn grant_role(conn: &mut Connection, unique_user_id: &String, role: &str) -> AppResult<bool> {
+    // Granting a role the user already has changes nothing
+    check_grantable_role(role)?;
+    conn.execute(
+        "INSERT OR IGNORE INTO user_privileges (user_id, privilege) VALUES (?1, ?2)",
+        params![unique_user_id, role]
+    )?;
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+pub fn revoke_role(conn: &mut Connection, unique_user_id: &String, role: &str) -> AppResult<bool> {
+    check_grantable_role(role)?;
+
+    let tx = conn.transaction()?;
+
+    // Never leave the forum without an admin
+    if role == privileges::ADMIN {
+        let admins: isize = tx.query_row(
+            "SELECT COUNT(*) FROM user_privileges WHERE privilege = ?1 AND user_id != ?2",
+            params![privileges::ADMIN, unique_user_id],
+            |row| row.get(0)
+        )?;
+        if admins == 0 {
+            return Err(AppError::Conflict(String::from("The last admin can't lose the admin role")));
+        }
+    }
+
+    let revoked = tx.execute(
+        "DELETE FROM user_privileges WHERE user_id = ?1 AND privilege = ?2",
+        params![unique_user_id, role]
+    )?;
+    if revoked == 0 {
+        return Err(AppError::NotFound(String::from("The user doesn't have that role")));
+    }
+    tx.commit()?;
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+pub fn delete_thread(conn: &mut Connection, thread_uid: &String, unique_user_id: &String, can_moderate: bool) -> AppResult<bool> {
+    // Find the author of the thread
+    let creator_uid: Option<isize> = conn.query_row(
+        "SELECT creator_uid FROM threads WHERE unique_id = ?1",
+        params![thread_uid],
+        |row| row.get(0)
+    ).optional()?;
+    let creator_uid = match creator_uid {
+        Some(val) => val.to_string(),
+        None => return Err(AppError::NotFound(String::from("Thread not found"))),
+    };
+
+    // Only the author or course staff may delete the thread
+    if &creator_uid != unique_user_id && !can_moderate {
+        return Err(AppError::Forbidden(String::from("Only the author or course staff can delete this thread")));
+    }
+
+    // Delete the thread along with its comments
+    let tx = conn.transaction()?;
+    tx.execute(
+        "DELETE FROM revisions WHERE target_type = 'comment' AND target_id IN (SELECT unique_id FROM comments WHERE thread_id = ?1)",
+        params![thread_uid]
+    )?;
+    tx.execute(
+        "DELETE FROM votes WHERE target_type = 'comment' AND target_id IN (SELECT unique_id FROM comments WHERE thread_id = ?1)",
+        params![thread_uid]
+    )?;
+    tx.execute("DELETE FROM revisions WHERE target_type = 'thread' AND target_id = ?1", params![thread_uid])?;
+    tx.execute("DELETE FROM votes WHERE target_type = 'thread' AND target_id = ?1", params![thread_uid])?;
+    tx.execute("DELETE FROM comments WHERE thread_id = ?1", params![thread_uid])?;
+    tx.execute("DELETE FROM thread_tags WHERE thread_id = ?1", params![thread_uid])?;
+    tx.execute("DELETE FROM threads WHERE unique_id = ?1", params![thread_uid])?;
+    tx.commit()?;
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+pub fn delete_comment(conn: &mut Connection, thread_uid: &String, comment_uid: &String, unique_user_id: &String, can_moderate: bool) -> AppResult<bool> {
+    // Find the author of the comment (making sure it belongs to the given thread)
+    let creator_uid: Option<isize> = conn.query_row(
+        "SELECT creator_uid FROM comments WHERE unique_id = ?1 AND thread_id = ?2",
+        params![comment_uid, thread_uid],
+        |row| row.get(0)
+    ).optional()?;
+    let creator_uid = match creator_uid {
+        Some(val) => val.to_string(),
+        None => return Err(AppError::NotFound(String::from("Comment not found"))),
+    };
+
+    // Only the author or course staff may delete the comment
+    if &creator_uid != unique_user_id && !can_moderate {
+        return Err(AppError::Forbidden(String::from("Only the author or course staff can delete this comment")));
+    }
+
+    // Delete the comment along with its revisions and votes
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM revisions WHERE target_type = 'comment' AND target_id = ?1", params![comment_uid])?;
+    tx.execute("DELETE FROM votes WHERE target_type = 'comment' AND target_id = ?1", params![comment_uid])?;
+    tx.execute("DELETE FROM comments WHERE unique_id = ?1", params![comment_uid])?;
+    tx.commit()?;
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+pub fn get_tags(conn: &mut Connection) -> AppResult<Vec<Tag>> {
+    // Craft the SQL query (tags created on the fly drop out of the list once nothing uses them,
+    // the ones course staff created stay)
+    let mut tags_query_statement = conn.prepare(
+        "SELECT tags.name, tags.description, tags.color, tags.predefined, COUNT(thread_tags.thread_id) AS thread_count \
+             FROM tags LEFT JOIN thread_tags ON thread_tags.tag_id = tags.unique_id \
+             GROUP BY tags.unique_id HAVING tags.predefined OR thread_count > 0 \
+             ORDER BY thread_count DESC, tags.name"
+    )?;
+
+    // Create iterator to iterate through matching DB rows
+    let row_iter = tags_query_statement.query_map([], |row| {
+        Ok(Tag {
+            name: row.get(0)?,
+            description: row.get(1)?,
+            color: row.get(2)?,
+            predefined: row.get(3)?,
+            thread_count: row.get(4)?,
+        })
+    })?;
+
+    // Vector to store tag structs in
+    let mut tags: Vec<Tag> = Vec::new();
+
+    // Iterate through the DB rows
+    for entry in row_iter {
+        tags.push(entry?);
+    }
+
+    // Return the vector of Tag structs
+    Ok(tags)
+}
+
+pub fn create_tag(conn: &mut Connection, name: &String, description: Option<&String>, color: Option<&String>) -> AppResult<bool> {
+    // Blank values are stored as missing
+    let description = description.filter(|description| !description.trim().is_empty());
+    let color = color.filter(|color| !color.is_empty());
+
+    // A tag that was created on the fly becomes predefined, keeping its threads
+    let predefined: Option<bool> = conn.query_row(
+        "SELECT predefined FROM tags WHERE name = ?1",
+        params![name],
+        |row| row.get(0)
+    ).optional()?;
+    match predefined {
+        Some(true) => return Err(AppError::Conflict(String::from("Tag already exists"))),
+        Some(false) => conn.execute(
+            "UPDATE tags SET description = ?1, color = ?2, predefined = 1 WHERE name = ?3",
+            params![description, color, name]
+        )?,
+        None => conn.execute(
+            "INSERT INTO tags (name, description, color, predefined, created_at) VALUES (?1, ?2, ?3, 1, ?4)",
+            params![name, description, color, Utc::now().to_rfc3339()]
+        )?,
+    };
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+pub fn update_tag(conn: &mut Connection, name: &String, description: Option<&String>, color: Option<&String>) -> AppResult<bool> {
+    // Make sure there is actually something to change
+    if description.is_none() && color.is_none() {
+        return Err(AppError::Validation(String::from("At least one of description or color must be provided")));
+    }
+
+    // Find the tag's current details
+    let current: Option<(Option<String>, Option<String>)> = conn.query_row(
+        "SELECT description, color FROM tags WHERE name = ?1",
+        params![name],
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).optional()?;
+    let (old_description, old_color) = match current {
+        Some(val) => val,
+        None => return Err(AppError::NotFound(String::from("Tag not found"))),
+    };
+
+    // Missing values are left as they are, blank ones clear the field
+    let updated = |new: Option<&String>, old: Option<String>| match new {
+        Some(new) if new.trim().is_empty() => None,
+        Some(new) => Some(new.clone()),
+        None => old,
+    };
+    conn.execute(
+        "UPDATE tags SET description = ?1, color = ?2 WHERE name = ?3",
+        params![updated(description, old_description), updated(color, old_color), name]
+    )?;
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+pub fn delete_tag(conn: &mut Connection, name: &String) -> AppResult<bool> {
+    // Find the tag, and how many threads still carry it
+    let thread_count: Option<i64> = conn.query_row(
+        "SELECT COUNT(thread_tags.thread_id) FROM tags LEFT JOIN thread_tags ON thread_tags.tag_id = tags.unique_id \
+             WHERE tags.name = ?1 GROUP BY tags.unique_id",
+        params![name],
+        |row| row.get(0)
+    ).optional()?;
+
+    // Threads are never left without their tags
+    match thread_count {
+        None => return Err(AppError::NotFound(String::from("Tag not found"))),
+        Some(count) if count > 0 => return Err(AppError::Conflict(format!("Tag is still used by {} thread(s)", count))),
+        Some(_) => (),
+    }
+    conn.execute("DELETE FROM tags WHERE name = ?1", params![name])?;
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+pub fn set_thread_upvote(conn: &mut Connection, thread_uid: &String, unique_user_id: &String, upvoted: bool) -> AppResult<bool> {
+    // Make sure the thread actually exists
+    if !thread_exists(conn, thread_uid)? {
+        return Err(AppError::NotFound(String::from("Thread not found")));
+    }
+
+    set_upvote(conn, "thread", thread_uid, unique_user_id, upvoted)
+}
+
+pub fn set_comment_upvote(conn: &mut Connection, thread_uid: &String, comment_uid: &String, unique_user_id: &String, upvoted: bool) -> AppResult<bool> {
+    // Make sure the comment exists (and belongs to the given thread)
+    let count: isize = conn.query_row(
+        "SELECT COUNT(*) FROM comments WHERE unique_id = ?1 AND thread_id = ?2",
+        params![comment_uid, thread_uid],
+        |row| row.get(0)
+    )?;
+    if count == 0 {
+        return Err(AppError::NotFound(String::from("Comment not found")));
+    }
+
+    set_upvote(conn, "comment", comment_uid, unique_user_id, upvoted)
+}
+
+fn set_upvote(conn: &mut Connection, target_type: &str, target_uid: &String, unique_user_id: &String, upvoted: bool) -> AppResult<bool> {
+    // Each user upvotes something at most once; the running total is kept on the thread or
+    // comment itself so that listings can be sorted by it
+    let tx = conn.transaction()?;
+    let changed = match upvoted {
+        true => tx.execute(
+            "INSERT OR IGNORE INTO votes (target_type, target_id, user_id) VALUES (?1, ?2, ?3)",
+            params![target_type, target_uid, unique_user_id]
+        )?,
+        false => tx.execute(
+            "DELETE FROM votes WHERE target_type = ?1 AND target_id = ?2 AND user_id = ?3",
+            params![target_type, target_uid, unique_user_id]
+        )?,
+    };
+    if changed > 0 {
+        let delta = if upvoted { 1 } else { -1 };
+        tx.execute(
+            &format!("UPDATE {}s SET upvotes = upvotes + ?1 WHERE unique_id = ?2", target_type),
+            params![delta, target_uid]
+        )?;
+    }
+    tx.commit()?;
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+// A thread as it is before an edit: creator, title, creation timestamp, tags, content and last edit time
+type ThreadVersion = (isize, String, String, Option<String>, String, Option<String>);
+
+pub fn edit_thread(conn: &mut Connection, thread_uid: &String, unique_user_id: &String, title: Option<&String>, tags: Option<&[String]>, content: Option<&String>, restrict_tags: bool) -> AppResult<bool> {
+    // Get current time (to be the edit timestamp)
+    let now = Utc::now();
+
+    // Make sure there is actually something to change
+    if title.is_none() && tags.is_none() && content.is_none() {
+        return Err(AppError::Validation(String::from("At least one of title, tags or content must be provided")));
+    }
+
+    // Find the current version of the thread (holding the write lock from here on, so concurrent
+    // edits can't both save the same version as their revision)
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let current: Option<ThreadVersion> = tx.query_row(
+        "SELECT creator_uid, title, creation_timestamp, tag, content, edited_at FROM threads WHERE unique_id = ?1",
+        params![thread_uid],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+    ).optional()?;
+    let (creator_uid, old_title, creation_timestamp, old_tag, old_content, edited_at) = match current {
+        Some(val) => val,
+        None => return Err(AppError::NotFound(String::from("Thread not found"))),
+    };
+
+    // Only the author may edit the thread
+    if &creator_uid.to_string() != unique_user_id {
+        return Err(AppError::Forbidden(String::from("Only the author can edit this thread")));
+    }
+
+    // Store the current version as a revision, then apply the edit
+    tx.execute(
+        "INSERT INTO \
+                revisions (target_type, target_id, version_timestamp, title, tag, content) \
+             VALUES ('thread', ?1, ?2, ?3, ?4, ?5)",
+        params![thread_uid, edited_at.unwrap_or(creation_timestamp), old_title, old_tag, old_content]
+    )?;
+    tx.execute(
+        "UPDATE threads SET title = ?1, tag = ?2, content = ?3, edited_at = ?4 WHERE unique_id = ?5",
+        params![
+            title.unwrap_or(&old_title),
+            tags.map(|tags| tags.join(" ")).or(old_tag),
+            content.unwrap_or(&old_content),
+            now.to_rfc3339(),
+            thread_uid
+        ]
+    )?;
+    if let Some(tags) = tags {
+        set_thread_tags(&tx, thread_uid, tags, restrict_tags)?;
+    }
+    tx.commit()?;
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+pub fn edit_comment(conn: &mut Connection, thread_uid: &String, comment_uid: &String, unique_user_id: &String, content: &String) -> AppResult<bool> {
+    // Get current time (to be the edit timestamp)
+    let now = Utc::now();
+
+    // Find the current version of the comment (making sure it belongs to the given thread, and
+    // holding the write lock from here on)
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let current: Option<(isize, String, String, Option<String>)> = tx.query_row(
+        "SELECT creator_uid, creation_timestamp, content, edited_at FROM comments WHERE unique_id = ?1 AND thread_id = ?2",
+        params![comment_uid, thread_uid],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    ).optional()?;
+    let (creator_uid, creation_timestamp, old_content, edited_at) = match current {
+        Some(val) => val,
+        None => return Err(AppError::NotFound(String::from("Comment not found"))),
+    };
+
+    // Only the author may edit the comment
+    if &creator_uid.to_string() != unique_user_id {
+        return Err(AppError::Forbidden(String::from("Only the author can edit this comment")));
+    }
+
+    // Store the current version as a revision, then apply the edit
+    tx.execute(
+        "INSERT INTO \
+                revisions (target_type, target_id, version_timestamp, content) \
+             VALUES ('comment', ?1, ?2, ?3)",
+        params![comment_uid, edited_at.unwrap_or(creation_timestamp), old_content]
+    )?;
+    tx.execute(
+        "UPDATE comments SET content = ?1, edited_at = ?2 WHERE unique_id = ?3",
+        params![content, now.to_rfc3339(), comment_uid]
+    )?;
+    tx.commit()?;
+
+    // If all succeeds, return true
+    Ok(true)
+}
+
+pub fn get_thread_revisions(conn: &mut Connection, thread_uid: &String) -> AppResult<Vec<Revision>> {
+    // Find the current version of the thread
+    let current: Option<Revision> = conn.query_row(
+        "SELECT title, creation_timestamp, tag, content, edited_at FROM threads WHERE unique_id = ?1",
+        params![thread_uid],
+        |row| {
+            let creation_timestamp: String = row.get(1)?;
+            let edited_at: Option<String> = row.get(4)?;
+            Ok(Revision {
+                version: 0,
+                timestamp: edited_at.unwrap_or(creation_timestamp),
+                title: row.get(0)?,
+                tag: row.get(2)?,
+                content: row.get(3)?,
+                diff: None,
+            })
+        }
+    ).optional()?;
+    let current = match current {
+        Some(val) => val,
+        None => return Err(AppError::NotFound(String::from("Thread not found"))),
+    };
+
+    build_revision_history(conn, "thread", thread_uid, current)
+}
+
+pub fn get_comment_revisions(conn: &mut Connection, thread_uid: &String, comment_uid: &String) -> AppResult<Vec<Revision>> {
+    // Find the current version of the comment (making sure it belongs to the given thread)
+    let current: Option<Revision> = conn.query_row(
+        "SELECT creation_timestamp, content, edited_at FROM comments WHERE unique_id = ?1 AND thread_id = ?2",
+        params![comment_uid, thread_uid],
+        |row| {
+            let creation_timestamp: String = row.get(0)?;
+            let edited_at: Option<String> = row.get(2)?;
+            Ok(Revision {
+                version: 0,
+                timestamp: edited_at.unwrap_or(creation_timestamp),
+                title: None,
+                tag: None,
+                content: row.get(1)?,
+                diff: None,
+            })
+        }
+    ).optional()?;
+    let current = match current {
+        Some(val) => val,
+        None => return Err(AppError::NotFound(String::from("Comment not found"))),
+    };
+
+    build_revision_history(conn, "comment", comment_uid, current)
+}
+
+fn build_revision_history(conn: &mut Connection, target_type: &str, target_uid: &String, current: Revision) -> AppResult<Vec<Revision>> {
+    // Craft the SQL query (oldest revision first)
+    let mut revisions_query_statement = conn.prepare(
+        "SELECT version_timestamp, title, tag, content FROM revisions \
+             WHERE target_type = ?1 AND target_id = ?2 \
+             ORDER BY unique_id ASC"
+    )?;
+
+    // Create iterator to iterate through matching DB rows
+    let row_iter = revisions_query_statement.query_map(params![target_type, target_uid], |row| {
+        Ok(Revision {
+            version: 0,
+            timestamp: row.get(0)?,
+            title: row.get(1)?,
+            tag: row.get(2)?,
+            content: row.get(3)?,
+            diff: None,
+        })
+    })?;
+
+    // Collect the prior versions followed by the current one
+    let mut revisions: Vec<Revision> = Vec::new();
+    for entry in row_iter {
+        revisions.push(entry?);
+    }
+    revisions.push(current);
+
+    // Number each version and diff it against the one before it
+    for index in 0..revisions.len() {
+        revisions[index].version = index + 1;
+        if index > 0 {
+            let (previous, rest) = revisions.split_at_mut(index);
+            let previous = &previous[index - 1];
+            let revision = &mut rest[0];
+            revision.diff = Some(RevisionDiff {
+                title: optional_diff(&previous.title, &revision.title),
+                tag: optional_diff(&previous.tag, &revision.tag),
+                content: line_diff(&previous.content, &revision.content),
+            });
+        }
+    }
+
+    // Return the vector of Revision structs
+    Ok(revisions)
+}
+
+fn optional_diff(old: &Option<String>, new: &Option<String>) -> Option<Vec<String>> {
+    // Only produce a diff for fields that actually changed
+    if old == new {
+        return None;
+    }
 
-pub fn delete_comment(conn: &mut Connection, thread_uid: &String) -> rusqlite::Result<bool> {
-    todo!("Implement delete_comment function")
+    let empty = String::new();
+    Some(line_diff(old.as_ref().unwrap_or(&empty), new.as_ref().unwrap_or(&empty)))
 }
@@ -0,0 +1,396 @@
+use std::net::IpAddr;
+use argon2::{Variant, Version};
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::figment::providers::Env;
+use rocket::serde::Deserialize;
+
+// Application configuration
+//
+// Read from Rocket's figment, so values come from Rocket.toml (per profile) and can be overridden
+// with HALP_* environment variables, using `__` to separate nested keys (e.g. HALP_AUTH__TOKEN_TTL_HOURS=24).
+#[derive(Deserialize, Clone, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct AppConfig {
+    #[serde(default)]
+    pub database: DbConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub password_hashing: HashingConfig,
+    #[serde(default)]
+    pub password_policy: PasswordPolicyConfig,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    #[serde(default)]
+    pub content: ContentConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub mail: MailConfig,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde", default)]
+pub struct DbConfig {
+    pub path: String,
+    pub in_memory: bool,
+    pub pool_size: u32,
+    pub busy_timeout_ms: u64,
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        DbConfig {
+            path: String::from("./test_db.sqlite"),
+            in_memory: false,
+            pool_size: 8,
+            busy_timeout_ms: 5000,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde", default)]
+pub struct AuthConfig {
+    pub token_ttl_hours: i64,
+    pub key_length: usize,
+    pub verification_ttl_hours: i64,
+    pub password_reset_ttl_minutes: i64,
+    pub require_verified_email: bool,
+    pub admin_usernames: Vec<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            token_ttl_hours: 240,
+            key_length: 32,
+            verification_ttl_hours: 48,
+            password_reset_ttl_minutes: 60,
+            require_verified_email: false,
+            admin_usernames: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde", default)]
+pub struct HashingConfig {
+    pub variant: String,
+    pub mem_cost: u32,
+    pub time_cost: u32,
+    pub lanes: u32,
+    pub hash_length: u32,
+    pub salt_length: usize,
+}
+
+impl Default for HashingConfig {
+    fn default() -> Self {
+        HashingConfig {
+            variant: String::from("argon2id"),
+            mem_cost: 19456,
+            time_cost: 2,
+            lanes: 1,
+            hash_length: 32,
+            salt_length: 16,
+        }
+    }
+}
+
+impl HashingConfig {
+    // Builds the argon2 configuration for hashing new passwords
+    pub fn argon2_config(&self) -> argon2::Config<'static> {
+        argon2::Config {
+            variant: Variant::from_str(&self.variant).unwrap_or_default(),
+            version: Version::Version13,
+            mem_cost: self.mem_cost,
+            time_cost: self.time_cost,
+            lanes: self.lanes,
+            hash_length: self.hash_length,
+            ..argon2::Config::default()
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde", default)]
+pub struct PasswordPolicyConfig {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub reject_common: bool,
+}
+
+impl Default for PasswordPolicyConfig {
+    fn default() -> Self {
+        PasswordPolicyConfig {
+            min_length: 10,
+            max_length: 128,
+            reject_common: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde", default)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_headers: vec![String::from("Content-Type"), String::from("x-auth-key")],
+            allow_credentials: true,
+            max_age_secs: 86400,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde", default)]
+pub struct ContentConfig {
+    pub max_title_length: usize,
+    pub max_tag_length: usize,
+    pub max_content_length: usize,
+    pub default_page_size: usize,
+    pub max_page_size: usize,
+    pub max_tags_per_thread: usize,
+    pub restrict_tags: bool,
+}
+
+impl Default for ContentConfig {
+    fn default() -> Self {
+        ContentConfig {
+            max_title_length: 200,
+            max_tag_length: 50,
+            max_content_length: 20000,
+            default_page_size: 20,
+            max_page_size: 100,
+            max_tags_per_thread: 5,
+            restrict_tags: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde", default)]
+pub struct RateLimitConfig {
+    pub window_secs: u64,
+    pub login_per_ip: u32,
+    pub login_per_username: u32,
+    pub register_per_ip: u32,
+    pub password_reset_per_ip: u32,
+    pub lockout_threshold: i64,
+    pub lockout_base_secs: i64,
+    pub lockout_max_secs: i64,
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            window_secs: 60,
+            login_per_ip: 20,
+            login_per_username: 10,
+            register_per_ip: 10,
+            password_reset_per_ip: 5,
+            lockout_threshold: 5,
+            lockout_base_secs: 30,
+            lockout_max_secs: 3600,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde", default)]
+pub struct MailConfig {
+    pub transport: String,
+    pub from: String,
+    pub public_url: String,
+    pub maildir: String,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub smtp_tls: String,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub smtp_timeout_secs: u64,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            transport: String::from("maildir"),
+            from: String::from("halp@localhost"),
+            public_url: String::from("http://localhost:8000"),
+            maildir: String::from("./mail"),
+            smtp_host: String::from("localhost"),
+            smtp_port: 587,
+            smtp_tls: String::from("starttls"),
+            smtp_username: None,
+            smtp_password: None,
+            smtp_timeout_secs: 10,
+        }
+    }
+}
+
+impl AppConfig {
+    // Checks the configuration for values the server can't run with
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<String> = Vec::new();
+
+        // Database
+        if !self.database.in_memory && self.database.path.trim().is_empty() {
+            errors.push(String::from("database.path must not be empty unless database.in_memory is set"));
+        }
+        if self.database.pool_size == 0 {
+            errors.push(String::from("database.pool_size must be at least 1"));
+        }
+
+        // Authentication keys
+        if self.auth.token_ttl_hours <= 0 {
+            errors.push(String::from("auth.token_ttl_hours must be positive"));
+        }
+        if self.auth.key_length < 16 {
+            errors.push(String::from("auth.key_length must be at least 16"));
+        }
+        if self.auth.verification_ttl_hours <= 0 {
+            errors.push(String::from("auth.verification_ttl_hours must be positive"));
+        }
+        if self.auth.password_reset_ttl_minutes <= 0 {
+            errors.push(String::from("auth.password_reset_ttl_minutes must be positive"));
+        }
+
+        // Password hashing
+        let hashing = &self.password_hashing;
+        if Variant::from_str(&hashing.variant).is_err() {
+            errors.push(format!("password_hashing.variant '{}' is not one of argon2i, argon2d or argon2id", hashing.variant));
+        }
+        if hashing.lanes == 0 {
+            errors.push(String::from("password_hashing.lanes must be at least 1"));
+        }
+        if hashing.time_cost == 0 {
+            errors.push(String::from("password_hashing.time_cost must be at least 1"));
+        }
+        if hashing.mem_cost < 8 * hashing.lanes {
+            errors.push(String::from("password_hashing.mem_cost must be at least 8 times password_hashing.lanes"));
+        }
+        if hashing.hash_length < 4 {
+            errors.push(String::from("password_hashing.hash_length must be at least 4"));
+        }
+        if hashing.salt_length < 8 {
+            errors.push(String::from("password_hashing.salt_length must be at least 8"));
+        }
+
+        // Password policy
+        if self.password_policy.min_length < 1 || self.password_policy.max_length < self.password_policy.min_length {
+            errors.push(String::from("password_policy.min_length must be at least 1 and no more than password_policy.max_length"));
+        }
+
+        // CORS (any site could make requests on a signed-in user's behalf with a credentialed wildcard)
+        if self.cors.allow_credentials && self.cors.allowed_origins.iter().any(|origin| origin == "*") {
+            errors.push(String::from("cors.allowed_origins can't be \"*\" while cors.allow_credentials is on"));
+        }
+        for origin in &self.cors.allowed_origins {
+            if origin != "*" && !origin.starts_with("http://") && !origin.starts_with("https://") {
+                errors.push(format!("cors.allowed_origins entry '{}' must be \"*\" or start with http:// or https://", origin));
+            }
+        }
+
+        // Rate limiting
+        let rate_limit = &self.rate_limit;
+        if rate_limit.window_secs == 0 {
+            errors.push(String::from("rate_limit.window_secs must be at least 1"));
+        }
+        if rate_limit.login_per_ip == 0 || rate_limit.login_per_username == 0 || rate_limit.register_per_ip == 0 || rate_limit.password_reset_per_ip == 0 {
+            errors.push(String::from("rate_limit request limits must all be at least 1"));
+        }
+        if rate_limit.lockout_threshold < 1 {
+            errors.push(String::from("rate_limit.lockout_threshold must be at least 1"));
+        }
+        for proxy in &rate_limit.trusted_proxies {
+            if proxy.parse::<IpAddr>().is_err() {
+                errors.push(format!("rate_limit.trusted_proxies entry '{}' must be an IP address", proxy));
+            }
+        }
+        if rate_limit.lockout_base_secs < 1 || rate_limit.lockout_max_secs < rate_limit.lockout_base_secs {
+            errors.push(String::from("rate_limit.lockout_base_secs must be at least 1 and no more than rate_limit.lockout_max_secs"));
+        }
+
+        // Mail
+        match self.mail.transport.as_str() {
+            "smtp" => {
+                if self.mail.smtp_host.trim().is_empty() {
+                    errors.push(String::from("mail.smtp_host must not be empty when mail.transport is \"smtp\""));
+                }
+                if self.mail.smtp_username.is_some() != self.mail.smtp_password.is_some() {
+                    errors.push(String::from("mail.smtp_username and mail.smtp_password must be set together"));
+                }
+                match self.mail.smtp_tls.as_str() {
+                    "starttls" | "tls" => (),
+                    "none" if self.mail.smtp_username.is_some() => {
+                        errors.push(String::from("mail.smtp_tls can't be \"none\" when SMTP credentials are set"));
+                    },
+                    "none" => (),
+                    other => errors.push(format!("mail.smtp_tls '{}' is not one of starttls, tls or none", other)),
+                }
+            },
+            "maildir" => {
+                if self.mail.maildir.trim().is_empty() {
+                    errors.push(String::from("mail.maildir must not be empty when mail.transport is \"maildir\""));
+                }
+            },
+            other => errors.push(format!("mail.transport '{}' is not one of smtp or maildir", other)),
+        }
+        if !self.mail.from.contains('@') {
+            errors.push(String::from("mail.from must be an email address"));
+        }
+
+        // Content limits
+        if self.content.max_title_length == 0 || self.content.max_tag_length == 0 || self.content.max_content_length == 0 {
+            errors.push(String::from("content limits must all be positive"));
+        }
+        if self.content.default_page_size == 0 || self.content.default_page_size > self.content.max_page_size {
+            errors.push(String::from("content.default_page_size must be between 1 and content.max_page_size"));
+        }
+        if self.content.max_tags_per_thread == 0 {
+            errors.push(String::from("content.max_tags_per_thread must be positive"));
+        }
+
+        errors
+    }
+}
+
+// Rocket's default figment plus our HALP_* environment overrides
+pub fn figment() -> Figment {
+    rocket::Config::figment().merge(Env::prefixed("HALP_").split("__").global())
+}
+
+// Fairing that extracts and validates the configuration, then hands it to Rocket
+pub fn init_config() -> AdHoc {
+    AdHoc::try_on_ignite("Application Config", |rocket| async move {
+        let config: AppConfig = match rocket.figment().extract() {
+            Ok(val) => val,
+            Err(e) => {
+                println!("Invalid configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        // Report every problem at once
+        let errors = config.validate();
+        if !errors.is_empty() {
+            for error in errors {
+                println!("Invalid configuration: {}", error);
+            }
+            return Err(rocket);
+        }
+
+        Ok(rocket.manage(config))
+    })
+}
@@ -0,0 +1,124 @@
+use rocket::{Orbit, Request, Response, Rocket};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::request::{FromRequest, Outcome};
+use crate::config::{AppConfig, CorsConfig};
+
+// CORS fairing
+//
+// Echoes the request's Origin back when it is on the configured allowlist, or sends a literal "*"
+// when every origin is allowed (config validation makes sure that never comes with credentials).
+// OPTIONS preflights are answered for every mounted route (by the preflight route in main.rs),
+// with the methods that route actually supports.
+pub struct Cors;
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        // The response depends on the Origin header, so caches must key on it
+        response.adjoin_header(Header::new("Vary", "Origin"));
+
+        // Only add CORS headers for allowed origins
+        let config = match request.rocket().state::<AppConfig>() {
+            Some(val) => &val.cors,
+            None => return,
+        };
+        let origin = match request.headers().get_one("Origin") {
+            Some(val) if origin_allowed(config, val) => val,
+            _ => return,
+        };
+
+        let wildcard = config.allowed_origins.iter().any(|allowed| allowed == "*");
+        response.set_header(Header::new("Access-Control-Allow-Origin", if wildcard { "*" } else { origin }.to_string()));
+        if config.allow_credentials && !wildcard {
+            response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        }
+
+        // Preflights additionally describe what the actual request may contain
+        if request.method() == Method::Options {
+            let methods: Vec<&str> = allowed_methods(request.rocket(), request)
+                .iter()
+                .map(|method| method.as_str())
+                .collect();
+            response.set_header(Header::new("Access-Control-Allow-Methods", methods.join(", ")));
+            response.set_header(Header::new("Access-Control-Allow-Headers", config.allowed_headers.join(", ")));
+            response.set_header(Header::new("Access-Control-Max-Age", config.max_age_secs.to_string()));
+        }
+    }
+}
+
+pub fn origin_allowed(config: &CorsConfig, origin: &str) -> bool {
+    config.allowed_origins.iter().any(|allowed| allowed == "*" || allowed == origin)
+}
+
+// Finds the methods of every mounted route whose path matches the request's path
+pub fn allowed_methods(rocket: &Rocket<Orbit>, request: &Request<'_>) -> Vec<Method> {
+    let request_segments: Vec<&str> = request.uri().path().segments().collect();
+
+    let mut methods: Vec<Method> = rocket.routes()
+        .filter(|route| route.method != Method::Options)
+        .filter(|route| path_matches(route.uri.path(), &request_segments))
+        .map(|route| route.method)
+        .collect();
+
+    // Anything that can be requested can also be preflighted
+    if !methods.is_empty() {
+        methods.push(Method::Options);
+    }
+    methods.sort_by_key(|method| method.as_str());
+    methods.dedup();
+
+    methods
+}
+
+fn path_matches(route_path: &str, request_segments: &[&str]) -> bool {
+    let route_segments: Vec<&str> = route_path.split('/').filter(|segment| !segment.is_empty()).collect();
+
+    for (index, route_segment) in route_segments.iter().enumerate() {
+        let dynamic = route_segment.starts_with('<') && route_segment.ends_with('>');
+
+        // A trailing <param..> segment matches whatever is left
+        if dynamic && route_segment.ends_with("..>") {
+            return true;
+        }
+
+        match request_segments.get(index) {
+            Some(request_segment) if dynamic || route_segment == request_segment => continue,
+            _ => return false,
+        }
+    }
+
+    route_segments.len() == request_segments.len()
+}
+
+// Request guard for preflight requests (fails if nothing is mounted at the requested path
+// or the requested method isn't supported there)
+pub struct Preflight;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Preflight {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let methods = allowed_methods(request.rocket(), request);
+        if methods.is_empty() {
+            return Outcome::Failure((Status::NotFound, ()));
+        }
+
+        // Check the method the browser intends to use, if it told us
+        if let Some(requested) = request.headers().get_one("Access-Control-Request-Method") {
+            if !methods.iter().any(|method| method.as_str().eq_ignore_ascii_case(requested)) {
+                return Outcome::Failure((Status::MethodNotAllowed, ()));
+            }
+        }
+
+        Outcome::Success(Preflight)
+    }
+}
@@ -0,0 +1,122 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use rand::{distributions::Alphanumeric, Rng};
+use rocket::fairing::AdHoc;
+use rusqlite::Connection;
+use crate::app_logic;
+use crate::config::{AppConfig, DbConfig};
+use crate::errors::{AppError, AppResult};
+use crate::migrations;
+
+// Pool types shared by the handlers and request guards
+pub type DbPool = r2d2::Pool<SqliteConnectionManager>;
+pub type DbConnection = r2d2::PooledConnection<SqliteConnectionManager>;
+
+// Connection manager that opens SQLite connections with our pragmas applied
+pub struct SqliteConnectionManager {
+    config: DbConfig,
+    // Keeps a shared in-memory database alive while the pool's own connections come and go
+    _keep_alive: Option<Mutex<Connection>>,
+}
+
+impl SqliteConnectionManager {
+    pub fn new(mut config: DbConfig) -> AppResult<Self> {
+        let mut keep_alive = None;
+
+        if config.in_memory {
+            // Give each in-memory database its own name so separate instances stay isolated
+            let suffix: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(16)
+                .map(char::from)
+                .collect();
+            config.path = format!("halp-memdb-{}", suffix);
+
+            // The database only lives as long as at least one connection to it is open
+            let conn = app_logic::connect_db(&config.path, true, Duration::from_millis(config.busy_timeout_ms))?;
+            keep_alive = Some(Mutex::new(conn));
+        }
+
+        Ok(SqliteConnectionManager { config, _keep_alive: keep_alive })
+    }
+}
+
+impl r2d2::ManageConnection for SqliteConnectionManager {
+    type Connection = Connection;
+    type Error = AppError;
+
+    fn connect(&self) -> AppResult<Connection> {
+        app_logic::connect_db(&self.config.path, self.config.in_memory, Duration::from_millis(self.config.busy_timeout_ms))
+    }
+
+    fn is_valid(&self, conn: &mut Connection) -> AppResult<()> {
+        conn.execute_batch("SELECT 1")?;
+        Ok(())
+    }
+
+    fn has_broken(&self, _conn: &mut Connection) -> bool {
+        false
+    }
+}
+
+// Gets a connection from the pool
+pub fn get_connection(pool: &DbPool) -> AppResult<DbConnection> {
+    match pool.get() {
+        Ok(conn) => Ok(conn),
+        Err(e) => {
+            println!("Encountered an error while getting a connection from the pool: {}", e);
+            Err(AppError::Unavailable(String::from("The database is currently unavailable")))
+        }
+    }
+}
+
+// Fairing that builds the pool, migrates the schema and hands the pool to Rocket
+pub fn init_pool() -> AdHoc {
+    AdHoc::try_on_ignite("SQLite Connection Pool", |rocket| async move {
+        // Read the database configuration (extracted and validated by the config fairing)
+        let config = match rocket.state::<AppConfig>() {
+            Some(val) => val.database.clone(),
+            None => {
+                println!("The database pool requires the application config to be attached first");
+                return Err(rocket);
+            }
+        };
+
+        // Build the pool
+        let manager = match SqliteConnectionManager::new(config.clone()) {
+            Ok(val) => val,
+            Err(e) => {
+                println!("Unable to open the database: {}", e);
+                return Err(rocket);
+            }
+        };
+        // Shared-cache in-memory databases fail on a lock conflict right away instead of waiting out
+        // busy_timeout, so their requests take turns on a single connection
+        let pool_size = if config.in_memory { 1 } else { config.pool_size };
+        let pool = match r2d2::Pool::builder()
+            .max_size(pool_size)
+            .build(manager) {
+            Ok(val) => val,
+            Err(e) => {
+                println!("Unable to create the database connection pool: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        // Bring the schema up to date
+        let migrate_result = get_connection(&pool).and_then(|mut conn| migrations::migrate(&mut conn, false));
+        match migrate_result {
+            Ok(applied) => {
+                for migration in applied {
+                    println!("Applied migration {:04} {}", migration.version, migration.name);
+                }
+            },
+            Err(e) => {
+                println!("Unable to migrate the database: {}", e);
+                return Err(rocket);
+            }
+        }
+
+        Ok(rocket.manage(pool))
+    })
+}
@@ -0,0 +1,147 @@
+// Line-based diffing used for revision histories
+//
+// Uses Myers' algorithm in its linear space form: each step finds the middle "snake" of the
+// shortest edit path and recurses on both halves, so memory grows with the number of lines rather
+// than with the product of both texts' line counts. Sections that would take more than
+// MAX_EDIT_COST edits to line up are shown as a plain replacement, which keeps the running time
+// bounded even for completely different texts.
+
+const MAX_EDIT_COST: usize = 1000;
+
+// Computes a line diff between two texts. Each returned line is prefixed with
+// "  " (unchanged), "- " (removed) or "+ " (added).
+pub fn line_diff(old: &str, new: &str) -> Vec<String> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+
+    let mut diff: Vec<String> = Vec::new();
+    diff_section(&old_lines, &new_lines, (0, 0), (old_lines.len(), new_lines.len()), &mut diff);
+    diff
+}
+
+// Diffs old[start.0..end.0] against new[start.1..end.1]
+fn diff_section(old: &[&str], new: &[&str], start: (usize, usize), end: (usize, usize), diff: &mut Vec<String>) {
+    let (mut left, mut top) = start;
+    let (mut right, mut bottom) = end;
+
+    // Lines both sides start or end with are unchanged
+    while left < right && top < bottom && old[left] == new[top] {
+        diff.push(format!("  {}", old[left]));
+        left += 1;
+        top += 1;
+    }
+    let mut common_suffix = 0;
+    while left < right && top < bottom && old[right - 1] == new[bottom - 1] {
+        right -= 1;
+        bottom -= 1;
+        common_suffix += 1;
+    }
+
+    if left == right || top == bottom {
+        replace(old, new, (left, top), (right, bottom), diff);
+    } else {
+        match middle_snake(old, new, (left, top), (right, bottom)) {
+            Some((snake_start, snake_end)) => {
+                diff_section(old, new, (left, top), snake_start, diff);
+                walk_snake(old, new, snake_start, snake_end, diff);
+                diff_section(old, new, snake_end, (right, bottom), diff);
+            },
+            None => replace(old, new, (left, top), (right, bottom), diff),
+        }
+    }
+
+    for line in &old[right..right + common_suffix] {
+        diff.push(format!("  {}", line));
+    }
+}
+
+// Shows a section as removed, then added
+fn replace(old: &[&str], new: &[&str], start: (usize, usize), end: (usize, usize), diff: &mut Vec<String>) {
+    for line in &old[start.0..end.0] {
+        diff.push(format!("- {}", line));
+    }
+    for line in &new[start.1..end.1] {
+        diff.push(format!("+ {}", line));
+    }
+}
+
+// A snake is at most one removal or addition, with unchanged lines on either side of it
+fn walk_snake(old: &[&str], new: &[&str], start: (usize, usize), end: (usize, usize), diff: &mut Vec<String>) {
+    let (mut x, mut y) = start;
+    let walk_diagonal = |x: &mut usize, y: &mut usize, diff: &mut Vec<String>| {
+        while *x < end.0 && *y < end.1 && old[*x] == new[*y] {
+            diff.push(format!("  {}", old[*x]));
+            *x += 1;
+            *y += 1;
+        }
+    };
+
+    walk_diagonal(&mut x, &mut y, diff);
+    if end.0 - x > end.1 - y {
+        diff.push(format!("- {}", old[x]));
+        x += 1;
+    } else if end.0 - x < end.1 - y {
+        diff.push(format!("+ {}", new[y]));
+        y += 1;
+    }
+    walk_diagonal(&mut x, &mut y, diff);
+}
+
+// Searches forwards from the start and backwards from the end at the same time until the two
+// searches overlap, returning the snake where they meet (None past MAX_EDIT_COST)
+fn middle_snake(old: &[&str], new: &[&str], start: (usize, usize), end: (usize, usize)) -> Option<((usize, usize), (usize, usize))> {
+    let (left, top) = (start.0 as isize, start.1 as isize);
+    let (right, bottom) = (end.0 as isize, end.1 as isize);
+    let delta = (right - left) - (bottom - top);
+    let odd = delta % 2 != 0;
+    let max = ((right - left) + (bottom - top) + 1) / 2;
+
+    // Furthest x reached on each diagonal k = x - y (relative to the start) going forwards, and
+    // furthest y reached on each diagonal c = k - delta going backwards
+    let offset = max + 1;
+    let mut forward = vec![0isize; 2 * max as usize + 3];
+    let mut backward = vec![0isize; 2 * max as usize + 3];
+    forward[(1 + offset) as usize] = left;
+    backward[(1 + offset) as usize] = bottom;
+    let at = |k: isize| (k + offset) as usize;
+
+    for d in 0..=max.min(MAX_EDIT_COST as isize) {
+        for k in (-d..=d).rev().step_by(2) {
+            let c = k - delta;
+            let (px, mut x) = match k == -d || (k != d && forward[at(k - 1)] < forward[at(k + 1)]) {
+                true => (forward[at(k + 1)], forward[at(k + 1)]),
+                false => (forward[at(k - 1)], forward[at(k - 1)] + 1),
+            };
+            let mut y = top + (x - left) - k;
+            let py = if d == 0 || x != px { y } else { y - 1 };
+            while x < right && y < bottom && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[at(k)] = x;
+            if odd && -d < c && c < d && y >= backward[at(c)] {
+                return Some(((px as usize, py as usize), (x as usize, y as usize)));
+            }
+        }
+
+        for c in (-d..=d).rev().step_by(2) {
+            let k = c + delta;
+            let (py, mut y) = match c == -d || (c != d && backward[at(c - 1)] > backward[at(c + 1)]) {
+                true => (backward[at(c + 1)], backward[at(c + 1)]),
+                false => (backward[at(c - 1)], backward[at(c - 1)] - 1),
+            };
+            let mut x = left + (y - top) + k;
+            let px = if d == 0 || y != py { x } else { x + 1 };
+            while x > left && y > top && old[x as usize - 1] == new[y as usize - 1] {
+                x -= 1;
+                y -= 1;
+            }
+            backward[at(c)] = y;
+            if !odd && -d <= k && k <= d && x <= forward[at(k)] {
+                return Some(((x as usize, y as usize), (px as usize, py as usize)));
+            }
+        }
+    }
+
+    None
+}
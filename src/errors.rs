@@ -0,0 +1,168 @@
+use std::fmt;
+use rocket::Request;
+use rocket::http::{Header, Status};
+use rocket::response::{self, Responder};
+use rocket::serde::Serialize;
+use rocket::serde::json::{Json, Value};
+use rocket::serde::json::serde_json::json;
+use rusqlite::ErrorCode;
+
+// Result alias used throughout the application logic
+pub type AppResult<T> = Result<T, AppError>;
+
+// A problem with one field of the request body
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: &str) -> Self {
+        FieldError {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+// Application Errors
+#[derive(Debug)]
+pub enum AppError {
+    Database(rusqlite::Error),
+    NotFound(String),
+    Conflict(String),
+    ConflictingFields(Vec<FieldError>),
+    Unauthorized(String),
+    Forbidden(String),
+    Validation(String),
+    InvalidFields(Vec<FieldError>),
+    RateLimited(String, u64),
+    Internal(String),
+    Unavailable(String),
+}
+
+impl AppError {
+    pub fn status(&self) -> Status {
+        match self {
+            AppError::Database(_) => Status::InternalServerError,
+            AppError::NotFound(_) => Status::NotFound,
+            AppError::Conflict(_) | AppError::ConflictingFields(_) => Status::Conflict,
+            AppError::Unauthorized(_) => Status::Unauthorized,
+            AppError::Forbidden(_) => Status::Forbidden,
+            AppError::Validation(_) | AppError::InvalidFields(_) => Status::UnprocessableEntity,
+            AppError::RateLimited(_, _) => Status::TooManyRequests,
+            AppError::Internal(_) => Status::InternalServerError,
+            AppError::Unavailable(_) => Status::ServiceUnavailable,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Database(_) => "database_error",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) | AppError::ConflictingFields(_) => "conflict",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::Validation(_) | AppError::InvalidFields(_) => "validation_failed",
+            AppError::RateLimited(_, _) => "rate_limited",
+            AppError::Internal(_) => "internal_error",
+            AppError::Unavailable(_) => "unavailable",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            // Don't leak database internals to the client
+            AppError::Database(_) => String::from("A database error occurred"),
+            AppError::ConflictingFields(_) => String::from("One or more fields conflict with existing data"),
+            AppError::InvalidFields(_) => String::from("One or more fields are invalid"),
+            AppError::NotFound(msg)
+            | AppError::Conflict(msg)
+            | AppError::Unauthorized(msg)
+            | AppError::Forbidden(msg)
+            | AppError::Validation(msg)
+            | AppError::RateLimited(msg, _)
+            | AppError::Internal(msg)
+            | AppError::Unavailable(msg) => msg.clone(),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Database(e) => write!(f, "database error: {}", e),
+            _ => write!(f, "{}: {}", self.code(), self.message()),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(e: rusqlite::Error) -> Self {
+        // Unique/foreign key violations are the client's fault, not ours
+        match &e {
+            rusqlite::Error::SqliteFailure(failure, _) if failure.code == ErrorCode::ConstraintViolation => {
+                AppError::Conflict(String::from("The request conflicts with existing data"))
+            },
+            _ => AppError::Database(e),
+        }
+    }
+}
+
+// Builds the JSON body shared by every error response
+pub fn error_body(code: &str, message: &str) -> Value {
+    json!({
+        "error": {
+            "code": code,
+            "message": message,
+        }
+    })
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for AppError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        // Log anything that is our fault
+        if self.status().code >= 500 {
+            println!("Encountered an error while handling {}: {}", request.uri(), self);
+        }
+
+        // Field-level problems are listed alongside the usual code and message
+        let mut body = error_body(self.code(), &self.message());
+        if let AppError::InvalidFields(fields) | AppError::ConflictingFields(fields) = &self {
+            body["error"]["fields"] = json!(fields);
+        }
+
+        let mut response = (self.status(), Json(body)).respond_to(request)?;
+
+        // Tell rate limited clients when they may try again
+        if let AppError::RateLimited(_, retry_after_secs) = self {
+            response.set_header(Header::new("Retry-After", retry_after_secs.to_string()));
+        }
+
+        Ok(response)
+    }
+}
+
+// Catchers (so that guard failures and unknown routes also return JSON)
+#[catch(default)]
+pub fn default_catcher(status: Status, _request: &Request) -> (Status, Json<Value>) {
+    let code = match status.code {
+        400 => "bad_request",
+        401 => "unauthorized",
+        403 => "forbidden",
+        404 => "not_found",
+        405 => "method_not_allowed",
+        409 => "conflict",
+        422 => "validation_failed",
+        429 => "rate_limited",
+        503 => "unavailable",
+        _ if status.code >= 500 => "internal_error",
+        _ => "error",
+    };
+
+    (status, Json(error_body(code, status.reason_lossy())))
+}
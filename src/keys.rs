@@ -0,0 +1,37 @@
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+
+// Authentication keys are only ever stored as SHA-256 digests, alongside a short prefix of the
+// plaintext key that is used to find candidate rows (and to tell sessions apart).
+pub const KEY_PREFIX_LENGTH: usize = 8;
+
+// Generates a new random authentication key
+pub fn generate_key(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+// Hex-encoded SHA-256 digest of an authentication key
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// The identifying prefix stored for an authentication key
+pub fn key_prefix(key: &str) -> String {
+    key.chars().take(KEY_PREFIX_LENGTH).collect()
+}
+
+// Compares two strings without short-circuiting on the first differing byte
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
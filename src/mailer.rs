@@ -0,0 +1,355 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use chrono::Utc;
+use native_tls::{TlsConnector, TlsStream};
+use rocket::fairing::AdHoc;
+use crate::config::{AppConfig, MailConfig};
+use crate::errors::{AppError, AppResult};
+use crate::keys;
+
+// An outgoing plain text email
+#[derive(Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// Mail transport
+//
+// Handlers only see `MailTransport` (a boxed Mailer), so the transport can be picked by configuration:
+// SMTP delivery in production, or a local Maildir during development and tests. Either way it sits
+// behind a MailQueue, so sending never holds up a request.
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> AppResult<()>;
+
+    // Waits until everything passed to send has been delivered (or has failed), so that tests can
+    // read what was sent
+    #[cfg(test)]
+    fn flush(&self) {}
+}
+
+pub type MailTransport = Box<dyn Mailer>;
+
+// Builds the RFC 5322 message for an email (with CRLF line endings)
+fn format_message(from: &str, email: &Email) -> String {
+    // Header values must not be able to start new headers
+    let header = |value: &str| value.replace(['\r', '\n'], " ");
+    let domain = from.rsplit('@').next().unwrap_or("localhost");
+
+    let mut message = String::new();
+    message.push_str(&format!("From: {}\r\n", header(from)));
+    message.push_str(&format!("To: {}\r\n", header(&email.to)));
+    message.push_str(&format!("Subject: {}\r\n", header(&email.subject)));
+    message.push_str(&format!("Date: {}\r\n", Utc::now().to_rfc2822()));
+    message.push_str(&format!("Message-ID: <{}@{}>\r\n", keys::generate_key(24), domain));
+    message.push_str("MIME-Version: 1.0\r\n");
+    message.push_str("Content-Type: text/plain; charset=utf-8\r\n");
+    message.push_str("Content-Transfer-Encoding: 8bit\r\n");
+    message.push_str("\r\n");
+    for line in email.body.lines() {
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+
+    message
+}
+
+// Delivers mail to an SMTP relay. The connection is encrypted with STARTTLS (or TLS from the start)
+// unless mail.smtp_tls is "none", which is only allowed without credentials, so AUTH PLAIN never
+// goes out in the clear.
+pub struct SmtpMailer {
+    config: MailConfig,
+}
+
+// The connection to the relay, before or after TLS has been set up
+enum SmtpStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Read for SmtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SmtpStream::Plain(stream) => stream.read(buf),
+            SmtpStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for SmtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            SmtpStream::Plain(stream) => stream.write(buf),
+            SmtpStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SmtpStream::Plain(stream) => stream.flush(),
+            SmtpStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl SmtpMailer {
+    pub fn new(config: MailConfig) -> Self {
+        SmtpMailer { config }
+    }
+
+    // Reads a (possibly multi-line) reply and checks its status code
+    fn expect_reply(reader: &mut BufReader<SmtpStream>, expected: u16) -> AppResult<()> {
+        loop {
+            let mut line = String::new();
+            if let Err(e) = reader.read_line(&mut line) {
+                return Err(AppError::Internal(format!("Unable to read from the SMTP server: {}", e)));
+            }
+
+            // "250-..." continues the reply, "250 ..." ends it
+            if line.as_bytes().get(3) == Some(&b'-') {
+                continue;
+            }
+            return match line.get(0..3).map(|code| code.parse::<u16>()) {
+                Some(Ok(code)) if code == expected => Ok(()),
+                _ => Err(AppError::Internal(format!("Unexpected SMTP reply: {}", line.trim_end()))),
+            };
+        }
+    }
+
+    fn command(reader: &mut BufReader<SmtpStream>, command: &str, expected: u16) -> AppResult<()> {
+        if let Err(e) = reader.get_mut().write_all(format!("{}\r\n", command).as_bytes()) {
+            return Err(AppError::Internal(format!("Unable to write to the SMTP server: {}", e)));
+        }
+        SmtpMailer::expect_reply(reader, expected)
+    }
+
+    // Wraps the connection in TLS, checking the relay's certificate against smtp_host
+    fn start_tls(&self, stream: TcpStream) -> AppResult<SmtpStream> {
+        let connector = match TlsConnector::new() {
+            Ok(val) => val,
+            Err(e) => return Err(AppError::Internal(format!("Unable to set up TLS: {}", e))),
+        };
+        match connector.connect(&self.config.smtp_host, stream) {
+            Ok(val) => Ok(SmtpStream::Tls(Box::new(val))),
+            Err(e) => Err(AppError::Internal(format!("Unable to establish TLS with the SMTP server: {}", e))),
+        }
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> AppResult<()> {
+        let config = &self.config;
+        let timeout = Duration::from_secs(config.smtp_timeout_secs);
+        let credentials = match (&config.smtp_username, &config.smtp_password) {
+            (Some(username), Some(password)) => Some(base64::encode(format!("\0{}\0{}", username, password))),
+            _ => None,
+        };
+        if credentials.is_some() && config.smtp_tls == "none" {
+            return Err(AppError::Internal(String::from("Refusing to send SMTP credentials without TLS")));
+        }
+
+        // Connect to the relay
+        let stream = match TcpStream::connect((config.smtp_host.as_str(), config.smtp_port)) {
+            Ok(val) => val,
+            Err(e) => return Err(AppError::Internal(format!("Unable to connect to the SMTP server: {}", e))),
+        };
+        if let Err(e) = stream.set_read_timeout(Some(timeout)).and_then(|_| stream.set_write_timeout(Some(timeout))) {
+            return Err(AppError::Internal(format!("Unable to set up the SMTP connection: {}", e)));
+        }
+        let stream = match config.smtp_tls.as_str() {
+            "tls" => self.start_tls(stream)?,
+            _ => SmtpStream::Plain(stream),
+        };
+        let mut reader = BufReader::new(stream);
+
+        // Greeting & handshake (starting over once the connection is encrypted)
+        SmtpMailer::expect_reply(&mut reader, 220)?;
+        SmtpMailer::command(&mut reader, "EHLO localhost", 250)?;
+        if config.smtp_tls == "starttls" {
+            SmtpMailer::command(&mut reader, "STARTTLS", 220)?;
+            let stream = match reader.into_inner() {
+                SmtpStream::Plain(stream) => stream,
+                SmtpStream::Tls(_) => return Err(AppError::Internal(String::from("SMTP connection is already encrypted"))),
+            };
+            reader = BufReader::new(self.start_tls(stream)?);
+            SmtpMailer::command(&mut reader, "EHLO localhost", 250)?;
+        }
+        if let Some(credentials) = credentials {
+            SmtpMailer::command(&mut reader, &format!("AUTH PLAIN {}", credentials), 235)?;
+        }
+
+        // Envelope
+        SmtpMailer::command(&mut reader, &format!("MAIL FROM:<{}>", config.from), 250)?;
+        SmtpMailer::command(&mut reader, &format!("RCPT TO:<{}>", email.to), 250)?;
+
+        // Message (lines starting with a dot are escaped by doubling it)
+        SmtpMailer::command(&mut reader, "DATA", 354)?;
+        let message: String = format_message(&config.from, email)
+            .split("\r\n")
+            .map(|line| if line.starts_with('.') { format!(".{}", line) } else { line.to_string() })
+            .collect::<Vec<String>>()
+            .join("\r\n");
+        SmtpMailer::command(&mut reader, &format!("{}.", message), 250)?;
+
+        // Hang up (the message has been accepted, so a failure here doesn't matter)
+        let _ = SmtpMailer::command(&mut reader, "QUIT", 221);
+
+        Ok(())
+    }
+}
+
+// Writes each message to a Maildir (tmp/ then renamed into new/), for local development and tests
+pub struct MaildirMailer {
+    config: MailConfig,
+    directory: PathBuf,
+}
+
+impl MaildirMailer {
+    pub fn new(config: MailConfig) -> AppResult<Self> {
+        let directory = PathBuf::from(&config.maildir);
+
+        // Create the Maildir layout
+        for subdirectory in ["tmp", "new", "cur"].iter() {
+            if let Err(e) = fs::create_dir_all(directory.join(subdirectory)) {
+                return Err(AppError::Internal(format!("Unable to create the maildir {}: {}", directory.display(), e)));
+            }
+        }
+
+        Ok(MaildirMailer { config, directory })
+    }
+}
+
+impl Mailer for MaildirMailer {
+    fn send(&self, email: &Email) -> AppResult<()> {
+        // Unique file name, so that concurrent deliveries never collide
+        let now = Utc::now();
+        let file_name = format!("{}.{:09}_{}.halp", now.timestamp(), now.timestamp_subsec_nanos(), keys::generate_key(12));
+        let tmp_path = self.directory.join("tmp").join(&file_name);
+        let new_path = self.directory.join("new").join(&file_name);
+
+        // Write the whole message before making it visible in new/
+        let result = fs::write(&tmp_path, format_message(&self.config.from, email))
+            .and_then(|_| fs::rename(&tmp_path, &new_path));
+        if let Err(e) = result {
+            return Err(AppError::Internal(format!("Unable to write mail to {}: {}", new_path.display(), e)));
+        }
+
+        Ok(())
+    }
+}
+
+// Hands emails over to a transport on a background thread, so that a slow or unreachable mail
+// server never blocks the async workers (or tells a client anything by failing). Delivery errors
+// are logged instead.
+pub struct MailQueue {
+    sender: Mutex<Sender<Email>>,
+    // Emails queued but not yet delivered, and a signal for when that reaches zero
+    pending: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl MailQueue {
+    pub fn new(transport: MailTransport) -> Self {
+        let (sender, receiver) = mpsc::channel::<Email>();
+        let pending = Arc::new((Mutex::new(0), Condvar::new()));
+
+        // Deliver in order until the queue is dropped
+        let worker_pending = pending.clone();
+        thread::spawn(move || {
+            for email in receiver {
+                if let Err(e) = transport.send(&email) {
+                    rocket::error!("Unable to send mail to {}: {}", email.to, e);
+                }
+                MailQueue::finished(&worker_pending);
+            }
+        });
+
+        MailQueue { sender: Mutex::new(sender), pending }
+    }
+
+    fn finished(pending: &(Mutex<usize>, Condvar)) {
+        let (count, idle) = pending;
+        let mut count = match count.lock() {
+            Ok(val) => val,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *count -= 1;
+        if *count == 0 {
+            idle.notify_all();
+        }
+    }
+}
+
+impl Mailer for MailQueue {
+    fn send(&self, email: &Email) -> AppResult<()> {
+        match self.pending.0.lock() {
+            Ok(mut val) => *val += 1,
+            Err(poisoned) => *poisoned.into_inner() += 1,
+        }
+        let sent = match self.sender.lock() {
+            Ok(val) => val.send(email.clone()),
+            Err(poisoned) => poisoned.into_inner().send(email.clone()),
+        };
+        if sent.is_err() {
+            MailQueue::finished(&self.pending);
+            return Err(AppError::Internal(String::from("The mail queue has shut down")));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn flush(&self) {
+        let (count, idle) = &*self.pending;
+        let mut count = match count.lock() {
+            Ok(val) => val,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        while *count > 0 {
+            count = match idle.wait(count) {
+                Ok(val) => val,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+        }
+    }
+}
+
+// Picks the configured transport
+pub fn build_transport(config: &MailConfig) -> AppResult<MailTransport> {
+    match config.transport.as_str() {
+        "smtp" => Ok(Box::new(SmtpMailer::new(config.clone()))),
+        "maildir" => Ok(Box::new(MaildirMailer::new(config.clone())?)),
+        other => Err(AppError::Internal(format!("Unknown mail transport '{}'", other))),
+    }
+}
+
+// Fairing that sets up the configured mail transport
+pub fn init_mailer() -> AdHoc {
+    AdHoc::try_on_ignite("Mail Transport", |rocket| async move {
+        // Read the mail configuration (extracted and validated by the config fairing)
+        let config = match rocket.state::<AppConfig>() {
+            Some(val) => val.mail.clone(),
+            None => {
+                println!("The mail transport requires the application config to be attached first");
+                return Err(rocket);
+            }
+        };
+
+        let transport = match build_transport(&config) {
+            Ok(val) => val,
+            Err(e) => {
+                println!("Unable to set up the mail transport: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        let queue: MailTransport = Box::new(MailQueue::new(transport));
+        Ok(rocket.manage(queue))
+    })
+}
@@ -1,43 +1,39 @@
 mod app_logic;
+mod config;
+mod cors;
+mod db;
+mod diff;
+mod errors;
+mod keys;
+mod mailer;
+mod migrations;
+mod pagination;
+mod privileges;
+mod rate_limit;
+mod search;
+mod validation;
+#[cfg(test)] mod tests;
 
 #[macro_use] extern crate rocket;
 
-use rocket::http::Header;
-use rocket::{Request, Response, State};
-use rocket::fairing::{Fairing, Info, Kind};
+use std::time::Duration;
+
+use rocket::{Build, Request, Rocket, State};
+use rocket::figment::Figment;
 use rocket::request::{FromRequest, Outcome};
 use rocket::serde::{Serialize, Deserialize, json::Json};
-use rocket::serde::json::serde_json::json;
+use rocket::serde::json::{Value, serde_json::json};
 use rocket::http::Status;
-
-// Set up CORS
-pub struct CORS;
-
-#[rocket::async_trait]
-impl Fairing for CORS {
-    fn info(&self) -> Info {
-        Info {
-            name: "Add CORS headers to responses",
-            kind: Kind::Response
-        }
-    }
-
-    async fn on_response<'r>(&self, _request: &'r Request<'_>, _response: &mut Response<'r>) {
-        // Set up response headers
-        // Note: These settings are _VERY_ permissive. They'll work for the demo though.
-        _response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
-        _response.set_header(Header::new("Access-Control-Allow-Methods", "POST, GET, DELETE, PATCH, OPTIONS"));
-        _response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
-        _response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
-    }
-}
+use app_logic::CurrentUser;
+use config::AppConfig;
+use db::DbPool;
+use errors::{AppError, AppResult};
+use mailer::MailTransport;
+use pagination::SortOrder;
+use privileges::{ManageTags, ManageUsers, ModerateContent, RequirePrivilege};
+use rate_limit::RateLimiter;
 
 // Data Structs
-struct DbState {
-    in_memory: bool,
-    db_path: String,
-}
-
 #[derive(Deserialize)]
 struct RegisterInfo<'r> {
     username: &'r str,
@@ -48,7 +44,8 @@ struct RegisterInfo<'r> {
 #[derive(Deserialize)]
 struct NewThread<'r> {
     title: &'r str,
-    tag: &'r str,
+    tag: Option<String>,
+    tags: Option<Vec<String>>,
     content: &'r str,
 }
 
@@ -57,77 +54,185 @@ struct NewComment<'r> {
     content: &'r str
 }
 
+#[derive(Deserialize)]
+struct ThreadEdit {
+    title: Option<String>,
+    tag: Option<String>,
+    tags: Option<Vec<String>>,
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CommentEdit {
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct ProfileEdit {
+    display_name: String,
+}
+
+#[derive(Deserialize)]
+struct NewTag {
+    name: String,
+    description: Option<String>,
+    color: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TagEdit {
+    description: Option<String>,
+    color: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct LoginInfo<'r> {
     username: &'r str,
     password: &'r str,
 }
 
-#[derive(Serialize)]
-struct Post<'r> {
-    unique_id: &'r str,
-    title: &'r str,
-    author_username: &'r str,
-    timestamp: &'r str,
-    tag: &'r str,
-    content: &'r str,
+#[derive(Deserialize)]
+struct PasswordChange {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordForgot {
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordReset {
+    token: String,
+    new_password: String,
+}
+
+// Query string of GET /threads (public, since the generated form code is)
+#[derive(FromForm)]
+pub struct ThreadListing {
+    pub sort: Option<String>,
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    pub tag: Option<String>,
+    pub author: Option<String>,
+    pub created_before: Option<String>,
+    pub created_after: Option<String>,
+    pub has_comments: Option<bool>,
+    pub unanswered: Option<bool>,
 }
 
 #[derive(Serialize)]
 struct ThreadsList {
-    threads: Vec<app_logic::Thread>
+    threads: Vec<app_logic::Thread>,
+    next_cursor: Option<String>,
 }
 
 #[derive(Serialize)]
 struct CommentsList {
-    comments: Vec<app_logic::Comment>
+    comments: Vec<app_logic::Comment>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TagsList {
+    tags: Vec<app_logic::Tag>,
+}
+
+#[derive(Serialize)]
+struct SearchResults {
+    results: Vec<app_logic::SearchResult>,
+}
+
+#[derive(Serialize)]
+struct SessionsList {
+    sessions: Vec<app_logic::Session>
+}
+
+#[derive(Serialize)]
+struct RevisionsList {
+    revisions: Vec<app_logic::Revision>
 }
 
 // Request Guards
-pub struct AuthenticationKey {
-    key_content: String,
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r CurrentUser {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        // Resolve the key at most once per request (every guard and handler shares the result)
+        let result = request.local_cache_async(async {
+            // Retrieve the managed DB pool
+            let db_pool = match request.rocket().state::<DbPool>() {
+                Some(res) => res,
+                None => return Err(Status::InternalServerError),
+            };
+
+            // Get the authentication key from the request header
+            let auth_key = match request.headers().get_one("x-auth-key") {
+                Some(res) => res.to_string(),
+                None => return Err(Status::Unauthorized),
+            };
+
+            // Get a connection to the database
+            let mut conn = match db::get_connection(db_pool) {
+                Ok(res) => res,
+                Err(_) => return Err(Status::ServiceUnavailable),
+            };
+
+            // Check the authentication key against the database and load its user
+            match app_logic::current_user(&mut conn, &auth_key) {
+                Ok(Some(user)) => Ok(user),
+                Ok(None) => Err(Status::Unauthorized),
+                Err(_) => Err(Status::InternalServerError),
+            }
+        }).await;
+
+        match result {
+            Ok(user) => Outcome::Success(user),
+            Err(status) => Outcome::Failure((*status, ())),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum AuthenticationKeyError {
-    Missing,
-    Invalid,
-    DbError
+pub struct ClientInfo {
+    details: app_logic::ClientDetails,
+}
+
+impl ClientInfo {
+    // Key used to rate limit this client (requests without a known address share one bucket)
+    fn ip_key(&self) -> &str {
+        self.details.ip_address.as_deref().unwrap_or("unknown")
+    }
 }
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for AuthenticationKey {
-    type Error = AuthenticationKeyError;
+impl<'r> FromRequest<'r> for ClientInfo {
+    type Error = ();
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        // Retrieve DB managed state
-        let db_state = match request.rocket().state::<DbState>() {
-            Some(res) => res,
-            None => return Outcome::Failure((Status::InternalServerError, AuthenticationKeyError::DbError)),
+        // Only believe X-Real-IP when it was set by one of the configured proxies
+        let remote_ip = request.remote().map(|remote| remote.ip());
+        let trusted_proxies = match request.rocket().state::<AppConfig>() {
+            Some(val) => &val.rate_limit.trusted_proxies,
+            None => return Outcome::Failure((Status::InternalServerError, ())),
         };
-
-        // Get the authentication key from the request header
-        let auth_key = match request.headers().get_one("x-auth-key") {
-            Some(res) => res.to_string(),
-            None => return Outcome::Failure((Status::Unauthorized, AuthenticationKeyError::Missing)),
+        let behind_trusted_proxy = match remote_ip {
+            Some(ip) => trusted_proxies.iter().any(|proxy| proxy.parse() == Ok(ip)),
+            None => false,
         };
-
-        // Get a connection to the database
-        let mut conn = match app_logic::connect_db(&db_state.db_path, db_state.in_memory) {
-            Ok(res) => res,
-            Err(_) => return Outcome::Failure((Status::InternalServerError, AuthenticationKeyError::DbError)),
+        let client_ip = match behind_trusted_proxy {
+            true => request.real_ip().or(remote_ip),
+            false => remote_ip,
         };
 
-        // Check the authentication key against the database and return
-        match app_logic::authenticate(&mut conn, &auth_key) {
-            Ok(res) => match res {
-                true => Outcome::Success(AuthenticationKey {
-                    key_content: auth_key
-                }),
-                false => Outcome::Failure((Status::Unauthorized, AuthenticationKeyError::Invalid)),
-            },
-            Err(_) => Outcome::Failure((Status::InternalServerError, AuthenticationKeyError::DbError)),
-        }
+        // Collect the details used to describe a session
+        Outcome::Success(ClientInfo {
+            details: app_logic::ClientDetails {
+                user_agent: request.headers().get_one("User-Agent").map(String::from),
+                ip_address: client_ip.map(|ip| ip.to_string()),
+            }
+        })
     }
 }
 
@@ -137,223 +242,610 @@ fn index() -> &'static str {
     "Hello, world!"
 }
 
+#[options("/<_..>")]
+fn preflight(_preflight: cors::Preflight) -> Status {
+    // CORS headers are added by the fairing
+    Status::NoContent
+}
+
 #[post("/register", data="<input>")]
-fn register(input: Json<RegisterInfo<'_>>, db_state: &State<DbState>) -> rocket::serde::json::Value {
-    // Variable to store whether or not the user registration was successful
-    let mut success = false;
+fn register(input: Json<RegisterInfo<'_>>, client: ClientInfo, limiter: &State<RateLimiter>, mailer: &State<MailTransport>, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Value> {
+    // Throttle registrations from the same address
+    let window = Duration::from_secs(config.rate_limit.window_secs);
+    limiter.check(&format!("register-ip:{}", client.ip_key()), config.rate_limit.register_per_ip, window)?;
+
+    // Check (and normalize) the submitted details
+    let registration = validation::validate_registration(input.username, input.email, input.password, &config.password_policy)?;
 
     // Connect to the DB
-    let mut conn = match app_logic::connect_db(&db_state.db_path, db_state.in_memory) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while connecting to the DB to register: {}", e);
-            success = false;
-            panic!("Panicked while trying to connect to DB to register!")
-        }
-    };
+    let mut conn = db::get_connection(db_pool)?;
 
     // Create the user
-    success = match app_logic::create_user(
+    let success = app_logic::create_user(
         &mut conn,
-        &input.username.to_string(),
-        &input.email.to_string(),
-        &input.password.to_string()
-    ) {
-        Ok(_) => true,
-        Err(e) => {
-            println!("Encountered an error while creating the user in the DB: {}", e);
-            false
-        }
-    };
+        &registration.username,
+        &registration.email,
+        &registration.password,
+        &config.password_hashing
+    )?;
+
+    // Send the verification link (the account exists either way, and the link can be resent)
+    let unique_user_id = app_logic::get_uid_from_username(&mut conn, &registration.username)?;
+    if let Err(e) = app_logic::send_email_verification(&mut conn, &unique_user_id, mailer.inner().as_ref(), config) {
+        rocket::warn!("Unable to send the verification email to {}: {}", registration.email, e);
+    }
 
-    // Return JSON (if we get this far!)
-    json!({
+    // Return JSON
+    Ok(json!({
         "success": success
-    })
+    }))
+}
+
+#[get("/verify-email/<token>")]
+fn verify_email(token: String, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Consume the verification token
+    let verify_result = app_logic::verify_email(&mut conn, &token)?;
+
+    // Return success status
+    Ok(json!({"success": verify_result}))
+}
+
+#[post("/verify-email/resend")]
+fn resend_verification(user: &CurrentUser, mailer: &State<MailTransport>, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Send a fresh link to the signed-in user (replacing any earlier one)
+    let send_result = app_logic::send_email_verification(&mut conn, &user.unique_id, mailer.inner().as_ref(), config)?;
+
+    // Return success status
+    Ok(json!({"success": send_result}))
 }
 
 #[post("/login", data="<input>")]
-fn login(input: Json<LoginInfo<'_>>, db_state: &State<DbState>) -> rocket::serde::json::Value {
+fn login(input: Json<LoginInfo<'_>>, client: ClientInfo, limiter: &State<RateLimiter>, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Value> {
+    // Throttle login attempts from the same address and against the same account
+    let window = Duration::from_secs(config.rate_limit.window_secs);
+    limiter.check(&format!("login-ip:{}", client.ip_key()), config.rate_limit.login_per_ip, window)?;
+    limiter.check(&format!("login-user:{}", input.username), config.rate_limit.login_per_username, window)?;
+
     // Connect to the DB
-    let mut conn = match app_logic::connect_db(&db_state.db_path, db_state.in_memory) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while connecting to the DB to register: {}", e);
-            panic!("Panicked while trying to connect to DB to log the user in!")
-        }
-    };
+    let mut conn = db::get_connection(db_pool)?;
 
-    let authentication_key = match app_logic::login(&mut conn, &String::from(input.username), &String::from(input.password)) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while trying to authenticate the user: {}", e);
-            panic!("Panicked while trying to log the user in!")
-        }
-    };
+    let authentication_key = app_logic::login(&mut conn, &String::from(input.username), &String::from(input.password), config, &client.details)?;
 
     // Return the authentication key for this user
-    json!({
+    Ok(json!({
         "auth_key": authentication_key.0,
         "expiration_datetime": authentication_key.1
+    }))
+}
+
+#[post("/logout")]
+fn logout(user: &CurrentUser, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Revoke the key used to make this request
+    let revoke_result = app_logic::revoke_key(&mut conn, &user.authentication_key)?;
+
+    // Return success status
+    Ok(json!({"success": revoke_result}))
+}
+
+#[post("/logout/all")]
+fn logout_all(user: &CurrentUser, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Revoke every key belonging to the signed-in user
+    let revoked = app_logic::revoke_all_keys(&mut conn, &user.unique_id)?;
+
+    // Return success status
+    Ok(json!({"success": true, "revoked": revoked}))
+}
+
+#[post("/password/change", data="<input>")]
+fn change_password(input: Json<PasswordChange>, user: &CurrentUser, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Change the signed-in user's password (signing out their other sessions)
+    let revoked = app_logic::change_password(
+        &mut conn,
+        &user.unique_id,
+        &input.current_password,
+        &input.new_password,
+        &user.authentication_key,
+        config
+    )?;
+
+    // Return success status
+    Ok(json!({"success": true, "revoked": revoked}))
+}
+
+#[post("/password/forgot", data="<input>")]
+fn forgot_password(input: Json<PasswordForgot>, client: ClientInfo, limiter: &State<RateLimiter>, mailer: &State<MailTransport>, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Value> {
+    // Throttle reset requests from the same address
+    let window = Duration::from_secs(config.rate_limit.window_secs);
+    limiter.check(&format!("password-reset-ip:{}", client.ip_key()), config.rate_limit.password_reset_per_ip, window)?;
+
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Email a reset token (if the address belongs to an account)
+    let email = validation::normalize_email(&input.email);
+    let request_result = app_logic::request_password_reset(&mut conn, &email, mailer.inner().as_ref(), config)?;
+
+    // Return success status
+    Ok(json!({"success": request_result}))
+}
+
+#[post("/password/reset", data="<input>")]
+fn reset_password(input: Json<PasswordReset>, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Consume the reset token and set the new password
+    let reset_result = app_logic::reset_password(&mut conn, &input.token, &input.new_password, config)?;
+
+    // Return success status
+    Ok(json!({"success": reset_result}))
+}
+
+#[get("/me")]
+fn get_me(user: &CurrentUser) -> Value {
+    // Describe the signed-in user
+    json!({
+        "unique_id": user.unique_id,
+        "username": user.username,
+        "display_name": user.display_name.as_ref().unwrap_or(&user.username),
+        "roles": user.roles,
+        "email_verified": user.email_verified,
     })
 }
 
-#[get("/threads")]
-fn get_threads(authentication_key: AuthenticationKey, db_state: &State<DbState>) -> Json<ThreadsList> {
+#[patch("/me", data="<input>")]
+fn edit_me(input: Json<ProfileEdit>, user: &CurrentUser, db_pool: &State<DbPool>) -> AppResult<Value> {
     // Connect to the DB
-    let mut conn = match app_logic::connect_db(&db_state.db_path, db_state.in_memory) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while connecting to the DB to get threads: {}", e);
-            panic!("Panicked while trying to connect to DB to get a list of threads!")
-        }
-    };
+    let mut conn = db::get_connection(db_pool)?;
 
-    // Get a vector of threads from the DB
-    let threads = match app_logic::get_threads(&mut conn) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while fetching the vector of threads: {}", e);
-            panic!("Panicked while fetching threads!")
-        }
+    // Change the name shown next to the user's threads & comments
+    let edit_result = app_logic::set_display_name(&mut conn, &user.unique_id, &input.display_name)?;
+
+    // Return success status
+    Ok(json!({"success": edit_result}))
+}
+
+#[get("/sessions")]
+fn get_sessions(user: &CurrentUser, db_pool: &State<DbPool>) -> AppResult<Json<SessionsList>> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Get the signed-in user's active sessions
+    let sessions = app_logic::get_sessions(&mut conn, &user.unique_id, &user.authentication_key)?;
+
+    // Return as JSON
+    Ok(Json(SessionsList { sessions }))
+}
+
+#[delete("/sessions/<session_id>")]
+fn revoke_session(session_id: String, user: &CurrentUser, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Revoke the session (only if it belongs to the signed-in user)
+    let revoke_result = app_logic::revoke_session(&mut conn, &user.unique_id, &session_id)?;
+
+    // Return success status
+    Ok(json!({"success": revoke_result}))
+}
+
+#[delete("/admin/users/<username>/lockout")]
+fn clear_lockout(username: String, _admin: RequirePrivilege<ManageUsers>, limiter: &State<RateLimiter>, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Lift the lockout in the DB and forget the account's recent attempts
+    let clear_result = app_logic::clear_lockout(&mut conn, &username)?;
+    limiter.reset(&format!("login-user:{}", username));
+
+    // Return success status
+    Ok(json!({"success": clear_result}))
+}
+
+#[get("/admin/users/<username>/privileges")]
+fn get_privileges(username: String, _admin: RequirePrivilege<ManageUsers>, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Look up the user's roles
+    let unique_user_id = app_logic::get_uid_from_username(&mut conn, &username)?;
+    let roles = app_logic::get_user_roles(&mut conn, &unique_user_id)?;
+
+    // Return JSON
+    Ok(json!({"username": username, "privileges": roles}))
+}
+
+#[put("/admin/users/<username>/privileges/<privilege>")]
+fn grant_privilege(username: String, privilege: String, admin: RequirePrivilege<ManageUsers>, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Grant the role
+    let unique_user_id = app_logic::get_uid_from_username(&mut conn, &username)?;
+    let grant_result = app_logic::grant_role(&mut conn, &unique_user_id, &privilege)?;
+    rocket::info!("User {} granted '{}' to {}", admin.unique_user_id, privilege, username);
+
+    // Return success status
+    Ok(json!({"success": grant_result}))
+}
+
+#[delete("/admin/users/<username>/privileges/<privilege>")]
+fn revoke_privilege(username: String, privilege: String, admin: RequirePrivilege<ManageUsers>, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Revoke the role
+    let unique_user_id = app_logic::get_uid_from_username(&mut conn, &username)?;
+    let revoke_result = app_logic::revoke_role(&mut conn, &unique_user_id, &privilege)?;
+    rocket::info!("User {} revoked '{}' from {}", admin.unique_user_id, privilege, username);
+
+    // Return success status
+    Ok(json!({"success": revoke_result}))
+}
+
+#[get("/threads?<listing..>")]
+fn get_threads(listing: ThreadListing, _user: &CurrentUser, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Json<ThreadsList>> {
+    // Check the listing options (newest first by default) and filters
+    let sort = match listing.sort {
+        Some(val) => SortOrder::parse(&val, &[SortOrder::Newest, SortOrder::Oldest, SortOrder::Active, SortOrder::Upvoted])?,
+        None => SortOrder::Newest,
     };
+    let limit = app_logic::check_page_size(&config.content, listing.limit)?;
+    let filters = app_logic::check_thread_filters(
+        listing.tag,
+        listing.author,
+        listing.created_before,
+        listing.created_after,
+        listing.has_comments,
+        listing.unanswered
+    )?;
+
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Get a page of matching threads from the DB
+    let page = app_logic::get_threads(&mut conn, &filters, sort, limit, listing.cursor.as_ref())?;
 
     // Create a serializable ThreadsList
     let threads_list = ThreadsList {
-        threads,
+        threads: page.items,
+        next_cursor: page.next_cursor,
     };
 
     // Return as JSON
-    Json(threads_list)
+    Ok(Json(threads_list))
 }
 
-#[get("/threads/<thread_id>/comments")]
-fn get_comments(thread_id: String, authentication_key: AuthenticationKey, db_state: &State<DbState>) -> Json<CommentsList> {
-    // Connect to the DB
-    let mut conn = match app_logic::connect_db(&db_state.db_path, db_state.in_memory) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while connecting to the DB to get comments: {}", e);
-            panic!("Panicked while trying to connect to DB to get a list of comments!")
-        }
+#[get("/threads/<thread_id>/comments?<sort>&<limit>&<cursor>")]
+fn get_comments(thread_id: String, sort: Option<String>, limit: Option<usize>, cursor: Option<String>, _user: &CurrentUser, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Json<CommentsList>> {
+    // Check the listing options (oldest first by default, so conversations read in order)
+    let sort = match sort {
+        Some(val) => SortOrder::parse(&val, &[SortOrder::Oldest, SortOrder::Newest, SortOrder::Upvoted])?,
+        None => SortOrder::Oldest,
     };
+    let limit = app_logic::check_page_size(&config.content, limit)?;
 
-    // Get a vector of Comments from the DB
-    let comments = match app_logic::get_thread_comments(&mut conn, &thread_id) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while fetching the vector of Comments: {}", e);
-            panic!("Panicked while fetching Comments!")
-        }
-    };
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
 
-    // Create a serializable ThreadsList
+    // Get a page of comments from the DB
+    let page = app_logic::get_thread_comments(&mut conn, &thread_id, sort, limit, cursor.as_ref())?;
+
+    // Create a serializable CommentsList
     let comments_list = CommentsList {
-        comments,
+        comments: page.items,
+        next_cursor: page.next_cursor,
     };
 
     // Return as JSON
-    Json(comments_list)
+    Ok(Json(comments_list))
 }
 
-#[post("/thread/create", data="<input>")]
-fn create_thread(input: Json<NewThread<'_>>, authentication_key: AuthenticationKey, db_state: &State<DbState>) -> rocket::serde::json::Value {
-    // Connect to the DB
-    let mut conn = match app_logic::connect_db(&db_state.db_path, db_state.in_memory) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while connecting to the DB to create a thread: {}", e);
-            panic!("Panicked while trying to connect to DB to create a thread!")
-        }
+#[get("/search?<q>&<tag>&<limit>")]
+fn search_content(q: Option<String>, tag: Option<String>, limit: Option<usize>, _user: &CurrentUser, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Json<SearchResults>> {
+    // Check the search options
+    let query = match q {
+        Some(val) => val,
+        None => return Err(AppError::Validation(String::from("q is required"))),
     };
+    let tag = tag.filter(|tag| !tag.trim().is_empty());
+    let limit = app_logic::check_page_size(&config.content, limit)?;
 
-    // Get the signed-in user
-    let unique_user_id = match app_logic::reverse_key_lookup(&mut conn, &authentication_key.key_content) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while trying to reverse lookup the authentication key: {}", e);
-            panic!("Panicked while trying to reverse lookup the authentication key!")
-        }
-    };
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
 
-    let username = match app_logic::get_username_from_uid(&mut conn, &unique_user_id) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while trying to lookup the username from a uid: {}", e);
-            panic!("Panicked while trying to lookup the username from a uid!")
-        }
-    };
+    // Get the best matching threads and comments
+    let results = app_logic::search_content(&mut conn, &query, tag.as_ref(), limit)?;
+
+    // Return as JSON
+    Ok(Json(SearchResults { results }))
+}
+
+#[get("/tags")]
+fn get_tags(_user: &CurrentUser, db_pool: &State<DbPool>) -> AppResult<Json<TagsList>> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Get the tags, most used first
+    let tags = app_logic::get_tags(&mut conn)?;
+
+    // Return as JSON
+    Ok(Json(TagsList { tags }))
+}
+
+#[post("/tags", data="<input>")]
+fn create_tag(input: Json<NewTag>, staff: RequirePrivilege<ManageTags>, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Value> {
+    // Check the tag's details
+    let name = app_logic::check_tag_details(&config.content, Some(&input.name), input.description.as_ref(), input.color.as_ref())?.unwrap_or_default();
+
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Create the tag
+    let create_result = app_logic::create_tag(&mut conn, &name, input.description.as_ref(), input.color.as_ref())?;
+    rocket::info!("User {} created tag '{}'", staff.unique_user_id, name);
+
+    // Return success status
+    Ok(json!({"success": create_result, "name": name}))
+}
+
+#[patch("/tags/<name>", data="<input>")]
+fn edit_tag(name: String, input: Json<TagEdit>, _staff: RequirePrivilege<ManageTags>, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Value> {
+    // Check the tag's new details
+    app_logic::check_tag_details(&config.content, None, input.description.as_ref(), input.color.as_ref())?;
+
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Update the tag
+    let edit_result = app_logic::update_tag(&mut conn, &validation::normalize_tag(&name), input.description.as_ref(), input.color.as_ref())?;
+
+    // Return success status
+    Ok(json!({"success": edit_result}))
+}
+
+#[delete("/tags/<name>")]
+fn delete_tag(name: String, staff: RequirePrivilege<ManageTags>, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Delete the tag (only once no thread carries it)
+    let name = validation::normalize_tag(&name);
+    let delete_result = app_logic::delete_tag(&mut conn, &name)?;
+    rocket::info!("User {} deleted tag '{}'", staff.unique_user_id, name);
+
+    // Return success status
+    Ok(json!({"success": delete_result}))
+}
+
+#[post("/thread/create", data="<input>")]
+fn create_thread(input: Json<NewThread<'_>>, user: &CurrentUser, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Value> {
+    // Check the thread against the configured content limits (a single tag can be given as `tag`)
+    app_logic::check_thread_limits(&config.content, Some(input.title), Some(input.content))?;
+    let tags = input.tags.clone().or_else(|| input.tag.clone().map(|tag| vec![tag])).unwrap_or_default();
+    let tags = app_logic::check_tags(&config.content, &tags)?;
+
+    // Optionally only let users with a verified email address post
+    if config.auth.require_verified_email && !user.email_verified {
+        return Err(AppError::Forbidden(String::from("Verify your email address before creating threads")));
+    }
+
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
 
     // Create the thread using the application logic function
-    let create_result = match app_logic::create_thread(&mut conn, &String::from(input.title), &username, &String::from(input.tag), &String::from(input.content)) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while creating the thread: {}", e);
-            panic!("Panicked while creating the thread!")
-        }
-    };
+    let create_result = app_logic::create_thread(
+        &mut conn,
+        &String::from(input.title),
+        &user.unique_id,
+        &tags,
+        &String::from(input.content),
+        config.content.restrict_tags
+    )?;
 
     // Return success status
-    json!({"success": create_result})
+    Ok(json!({"success": create_result}))
 }
 
 #[post("/thread/<thread_id>/create_comment", data="<input>")]
-fn create_comment(thread_id: String, input: Json<NewComment<'_>>, authentication_key: AuthenticationKey, db_state: &State<DbState>) -> rocket::serde::json::Value {
+fn create_comment(thread_id: String, input: Json<NewComment<'_>>, user: &CurrentUser, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Value> {
+    // Check the comment against the configured content limits
+    app_logic::check_length("content", input.content, config.content.max_content_length)?;
+
     // Connect to the DB
-    let mut conn = match app_logic::connect_db(&db_state.db_path, db_state.in_memory) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while connecting to the DB to create a comment: {}", e);
-            panic!("Panicked while trying to connect to DB to create a comment!")
-        }
-    };
+    let mut conn = db::get_connection(db_pool)?;
 
-    // Get the signed-in user
-    let unique_user_id = match app_logic::reverse_key_lookup(&mut conn, &authentication_key.key_content) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while trying to reverse lookup the authentication key: {}", e);
-            panic!("Panicked while trying to reverse lookup the authentication key!")
-        }
-    };
+    // Create the comment using the application logic function
+    let create_result = app_logic::create_comment(&mut conn, &thread_id, &user.unique_id, &String::from(input.content))?;
 
-    let username = match app_logic::get_username_from_uid(&mut conn, &unique_user_id) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while trying to lookup the username from a uid: {}", e);
-            panic!("Panicked while trying to lookup the username from a uid!")
-        }
-    };
+    // Return success status
+    Ok(json!({"success": create_result}))
+}
 
-    // Create the thread using the application logic function
-    let create_result = match app_logic::create_comment(&mut conn, &String::from(thread_id), &username, &String::from(input.content)) {
-        Ok(val) => val,
-        Err(e) => {
-            println!("Encountered an error while creating the comment: {}", e);
-            panic!("Panicked while creating the comment!")
-        }
+#[delete("/thread/<thread_id>")]
+fn delete_thread(thread_id: String, user: &CurrentUser, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Delete the thread (and its comments) using the application logic function
+    let delete_result = app_logic::delete_thread(&mut conn, &thread_id, &user.unique_id, user.has_permission::<ModerateContent>())?;
+
+    // Return success status
+    Ok(json!({"success": delete_result}))
+}
+
+#[delete("/thread/<thread_id>/comment/<comment_id>")]
+fn delete_comment(thread_id: String, comment_id: String, user: &CurrentUser, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Delete the comment using the application logic function
+    let delete_result = app_logic::delete_comment(&mut conn, &thread_id, &comment_id, &user.unique_id, user.has_permission::<ModerateContent>())?;
+
+    // Return success status
+    Ok(json!({"success": delete_result}))
+}
+
+#[put("/thread/<thread_id>/vote")]
+fn upvote_thread(thread_id: String, user: &CurrentUser, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Upvote the thread (upvoting twice changes nothing)
+    let vote_result = app_logic::set_thread_upvote(&mut conn, &thread_id, &user.unique_id, true)?;
+
+    // Return success status
+    Ok(json!({"success": vote_result}))
+}
+
+#[delete("/thread/<thread_id>/vote")]
+fn remove_thread_upvote(thread_id: String, user: &CurrentUser, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Take back the upvote (if there was one)
+    let vote_result = app_logic::set_thread_upvote(&mut conn, &thread_id, &user.unique_id, false)?;
+
+    // Return success status
+    Ok(json!({"success": vote_result}))
+}
+
+#[put("/thread/<thread_id>/comment/<comment_id>/vote")]
+fn upvote_comment(thread_id: String, comment_id: String, user: &CurrentUser, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Upvote the comment (upvoting twice changes nothing)
+    let vote_result = app_logic::set_comment_upvote(&mut conn, &thread_id, &comment_id, &user.unique_id, true)?;
+
+    // Return success status
+    Ok(json!({"success": vote_result}))
+}
+
+#[delete("/thread/<thread_id>/comment/<comment_id>/vote")]
+fn remove_comment_upvote(thread_id: String, comment_id: String, user: &CurrentUser, db_pool: &State<DbPool>) -> AppResult<Value> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Take back the upvote (if there was one)
+    let vote_result = app_logic::set_comment_upvote(&mut conn, &thread_id, &comment_id, &user.unique_id, false)?;
+
+    // Return success status
+    Ok(json!({"success": vote_result}))
+}
+
+#[patch("/thread/<thread_id>", data="<input>")]
+fn edit_thread(thread_id: String, input: Json<ThreadEdit>, user: &CurrentUser, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Value> {
+    // Check the edit against the configured content limits
+    app_logic::check_thread_limits(&config.content, input.title.as_deref(), input.content.as_deref())?;
+    let tags = match input.tags.clone().or_else(|| input.tag.clone().map(|tag| vec![tag])) {
+        Some(tags) => Some(app_logic::check_tags(&config.content, &tags)?),
+        None => None,
     };
 
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Edit the thread using the application logic function
+    let edit_result = app_logic::edit_thread(
+        &mut conn,
+        &thread_id,
+        &user.unique_id,
+        input.title.as_ref(),
+        tags.as_deref(),
+        input.content.as_ref(),
+        config.content.restrict_tags
+    )?;
+
+    // Return success status
+    Ok(json!({"success": edit_result}))
+}
+
+#[patch("/thread/<thread_id>/comment/<comment_id>", data="<input>")]
+fn edit_comment(thread_id: String, comment_id: String, input: Json<CommentEdit>, user: &CurrentUser, db_pool: &State<DbPool>, config: &State<AppConfig>) -> AppResult<Value> {
+    // Check the edit against the configured content limits
+    app_logic::check_length("content", &input.content, config.content.max_content_length)?;
+
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Edit the comment using the application logic function
+    let edit_result = app_logic::edit_comment(&mut conn, &thread_id, &comment_id, &user.unique_id, &input.content)?;
+
     // Return success status
-    json!({"success": create_result})
+    Ok(json!({"success": edit_result}))
+}
+
+#[get("/thread/<thread_id>/revisions")]
+fn get_thread_revisions(thread_id: String, _user: &CurrentUser, db_pool: &State<DbPool>) -> AppResult<Json<RevisionsList>> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Get the thread's revision history (oldest first, each diffed against the previous)
+    let revisions = app_logic::get_thread_revisions(&mut conn, &thread_id)?;
+
+    // Return as JSON
+    Ok(Json(RevisionsList { revisions }))
+}
+
+#[get("/thread/<thread_id>/comment/<comment_id>/revisions")]
+fn get_comment_revisions(thread_id: String, comment_id: String, _user: &CurrentUser, db_pool: &State<DbPool>) -> AppResult<Json<RevisionsList>> {
+    // Connect to the DB
+    let mut conn = db::get_connection(db_pool)?;
+
+    // Get the comment's revision history (oldest first, each diffed against the previous)
+    let revisions = app_logic::get_comment_revisions(&mut conn, &thread_id, &comment_id)?;
+
+    // Return as JSON
+    Ok(Json(RevisionsList { revisions }))
 }
 
 // Launch
-#[launch]
-fn rocket() -> _ {
-    // Init variables
-    let db_state = DbState {
-        in_memory: false,
-        db_path: String::from("./test_db.sqlite"),
-    };
+// `backend` serves the API, and `backend migrate [status | up] [--dry-run]` manages the schema
+#[rocket::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("migrate") {
+        std::process::exit(migrations::run_cli(&args[1..], config::figment()));
+    }
 
-    // Run initial setup & get DB connection
-    let mut db_conn = app_logic::connect_db(&db_state.db_path, db_state.in_memory).unwrap();
-    app_logic::setup_database(&mut db_conn).unwrap();
+    if let Err(e) = build_rocket(config::figment()).launch().await {
+        println!("Unable to launch the server: {}", e);
+        std::process::exit(1);
+    }
+}
 
+// Builds the server from the given configuration (tests supply their own)
+fn build_rocket(figment: Figment) -> Rocket<Build> {
     // Run Rocket setup
-    rocket::build()
-        .manage(db_state)  // Manage DB state
-        .attach(CORS)
-        .mount("/", routes![index, register, login, get_threads, get_comments, create_thread, create_comment])
+    rocket::custom(figment)
+        .attach(config::init_config())  // Extract & validate the application config
+        .attach(db::init_pool())  // Manage the DB connection pool (and run pending migrations)
+        .attach(privileges::init_admins())  // Give the configured users the admin role
+        .attach(mailer::init_mailer())  // Set up the configured mail transport
+        .attach(cors::Cors)  // Add CORS headers (preflights are answered by the preflight route)
+        .manage(RateLimiter::new())  // Throttle login & registration attempts
+        .register("/", catchers![errors::default_catcher])
+        .mount("/", routes![index, preflight, register, verify_email, resend_verification, login, logout, logout_all,
+            get_me, edit_me, get_sessions, revoke_session, change_password, forgot_password, reset_password,
+            clear_lockout, get_privileges, grant_privilege, revoke_privilege,
+            get_tags, create_tag, edit_tag, delete_tag,
+            get_threads, get_comments, search_content, create_thread, create_comment, delete_thread, delete_comment,
+            upvote_thread, remove_thread_upvote, upvote_comment, remove_comment_upvote,
+            edit_thread, edit_comment, get_thread_revisions, get_comment_revisions])
 }
\ No newline at end of file
@@ -0,0 +1,521 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use chrono::Utc;
+use rocket::figment::Figment;
+use rusqlite::{params, Connection, TransactionBehavior};
+use crate::app_logic;
+use crate::config::AppConfig;
+use crate::errors::{AppError, AppResult};
+use crate::keys;
+use crate::validation;
+
+// Schema migrations
+//
+// Every schema change is a numbered migration, embedded in the binary and applied in order. Applied
+// versions are recorded in the schema_migrations table, so each migration only ever runs once per
+// database. Never edit a migration that has shipped; add a new one instead.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    step: Step,
+}
+
+enum Step {
+    Sql(&'static str),
+    Rust(fn(&Connection) -> AppResult<()>),
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "create_tables", step: Step::Sql(CREATE_TABLES) },
+    Migration { version: 2, name: "add_legacy_columns", step: Step::Rust(add_legacy_columns) },
+    Migration { version: 3, name: "drop_password_salt", step: Step::Rust(drop_password_salt) },
+    Migration { version: 4, name: "staff_roles", step: Step::Sql(STAFF_ROLES) },
+    Migration { version: 5, name: "hash_plaintext_keys", step: Step::Rust(hash_plaintext_keys) },
+    Migration { version: 6, name: "hot_path_indexes", step: Step::Sql(HOT_PATH_INDEXES) },
+    Migration { version: 7, name: "listing_sort_keys", step: Step::Sql(LISTING_SORT_KEYS) },
+    Migration { version: 8, name: "search_index", step: Step::Sql(SEARCH_INDEX) },
+    Migration { version: 9, name: "tags", step: Step::Rust(create_tags) },
+    Migration { version: 10, name: "display_names", step: Step::Sql(DISPLAY_NAMES) },
+    Migration { version: 11, name: "search_without_markers", step: Step::Sql(SEARCH_WITHOUT_MARKERS) },
+];
+
+// Whether a migration has been applied, and when
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: &'static str,
+    pub applied_at: Option<String>,
+}
+
+fn applied_migrations(conn: &Connection) -> AppResult<HashMap<i64, String>> {
+    // Create the bookkeeping table the first time around
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations ( \
+                version INTEGER PRIMARY KEY, \
+                name TEXT NOT NULL, \
+                applied_at TEXT NOT NULL \
+            );",
+        []
+    )?;
+
+    let mut applied_query = conn.prepare("SELECT version, applied_at FROM schema_migrations")?;
+    let applied_iter = applied_query.query_map([], |row| {
+        let version: i64 = row.get(0)?;
+        let applied_at: String = row.get(1)?;
+        Ok((version, applied_at))
+    })?;
+
+    let mut applied: HashMap<i64, String> = HashMap::new();
+    for entry in applied_iter {
+        let (version, applied_at) = entry?;
+        applied.insert(version, applied_at);
+    }
+
+    // Refuse to touch a database that a newer build has already migrated
+    if let Some(version) = applied.keys().find(|version| !MIGRATIONS.iter().any(|m| m.version == **version)) {
+        return Err(AppError::Internal(format!("The database has unknown migration {} applied (is this build out of date?)", version)));
+    }
+
+    Ok(applied)
+}
+
+pub fn status(conn: &mut Connection) -> AppResult<Vec<MigrationStatus>> {
+    // Read-only: the bookkeeping table is created in a transaction that is rolled back
+    let tx = conn.transaction()?;
+    let mut applied = applied_migrations(&tx)?;
+    drop(tx);
+
+    Ok(MIGRATIONS.iter().map(|migration| MigrationStatus {
+        version: migration.version,
+        name: migration.name,
+        applied_at: applied.remove(&migration.version),
+    }).collect())
+}
+
+pub fn migrate(conn: &mut Connection, dry_run: bool) -> AppResult<Vec<&'static Migration>> {
+    // Applies every pending migration in a single transaction, so a failure leaves the database
+    // untouched. A dry run applies them the same way and then rolls back.
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let applied = applied_migrations(&tx)?;
+
+    let mut pending: Vec<&'static Migration> = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| !applied.contains_key(&m.version)) {
+        let result = match migration.step {
+            Step::Sql(sql) => tx.execute_batch(sql).map_err(AppError::from),
+            Step::Rust(apply) => apply(&tx),
+        };
+        if let Err(e) = result {
+            return Err(AppError::Internal(format!("Migration {} ({}) failed: {}", migration.version, migration.name, e)));
+        }
+
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, ?2, ?3)",
+            params![migration.version, migration.name, Utc::now().to_rfc3339()]
+        )?;
+        pending.push(migration);
+    }
+
+    if !dry_run {
+        tx.commit()?;
+    }
+
+    Ok(pending)
+}
+
+// Command line interface: `backend migrate [status | up] [--dry-run]`
+pub fn run_cli(args: &[String], figment: Figment) -> i32 {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let command = args.iter().find(|arg| !arg.starts_with("--")).map(String::as_str).unwrap_or("up");
+
+    // Open the configured database
+    let config: AppConfig = match figment.extract() {
+        Ok(val) => val,
+        Err(e) => {
+            println!("Invalid configuration: {}", e);
+            return 1;
+        }
+    };
+    let busy_timeout = Duration::from_millis(config.database.busy_timeout_ms);
+    let mut conn = match app_logic::connect_db(&config.database.path, config.database.in_memory, busy_timeout) {
+        Ok(val) => val,
+        Err(e) => {
+            println!("Unable to open the database: {}", e);
+            return 1;
+        }
+    };
+
+    let result = match command {
+        "status" => status(&mut conn).map(|migrations| {
+            for migration in migrations {
+                match migration.applied_at {
+                    Some(applied_at) => println!("{:04} {:<24} applied {}", migration.version, migration.name, applied_at),
+                    None => println!("{:04} {:<24} pending", migration.version, migration.name),
+                }
+            }
+        }),
+        "up" => migrate(&mut conn, dry_run).map(|migrations| {
+            let verb = if dry_run { "Would apply" } else { "Applied" };
+            for migration in &migrations {
+                println!("{} {:04} {}", verb, migration.version, migration.name);
+            }
+            if migrations.is_empty() {
+                println!("The database is up to date");
+            }
+        }),
+        other => {
+            println!("Unknown migrate command '{}' (expected 'status' or 'up')", other);
+            return 2;
+        }
+    };
+
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("{}", e);
+            1
+        }
+    }
+}
+
+// Migrations
+
+// The schema as of the first migration (tables in databases that predate migrations are left as
+// they are and brought up to date by the migrations that follow)
+const CREATE_TABLES: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        unique_id INTEGER PRIMARY KEY,
+        username TEXT UNIQUE NOT NULL,
+        email TEXT UNIQUE,
+        password_hash TEXT NOT NULL,
+        registration_datetime TEXT,
+        failed_login_count INTEGER NOT NULL DEFAULT 0,
+        last_failed_login TEXT,
+        locked_until TEXT,
+        email_verified INTEGER NOT NULL DEFAULT 0
+    );
+
+    CREATE TABLE IF NOT EXISTS authentication_keys (
+        unique_id INTEGER PRIMARY KEY,
+        user_id INTEGER NOT NULL,
+        authentication_key TEXT NOT NULL,
+        key_prefix TEXT,
+        expiration TEXT NOT NULL,
+        created_at TEXT,
+        last_used TEXT,
+        user_agent TEXT,
+        ip_address TEXT,
+        FOREIGN KEY (user_id) references users(unique_id) ON DELETE CASCADE
+    );
+
+    CREATE TABLE IF NOT EXISTS user_privileges (
+        privilege_unique_id INTEGER PRIMARY KEY,
+        user_id INTEGER NOT NULL,
+        privilege TEXT NOT NULL,
+        FOREIGN KEY (user_id) references users(unique_id) ON DELETE CASCADE
+    );
+
+    CREATE TABLE IF NOT EXISTS threads (
+        unique_id INTEGER PRIMARY KEY,
+        title TEXT NOT NULL,
+        creator_uid INTEGER NOT NULL,
+        creation_timestamp TEXT,
+        tag TEXT,
+        content TEXT NOT NULL,
+        edited_at TEXT,
+        FOREIGN KEY (creator_uid) references users(unique_id) ON DELETE CASCADE
+    );
+
+    CREATE TABLE IF NOT EXISTS comments (
+        unique_id INTEGER PRIMARY KEY,
+        thread_id INTEGER NOT NULL,
+        creator_uid INTEGER NOT NULL,
+        creation_timestamp TEXT,
+        content TEXT NOT NULL,
+        edited_at TEXT,
+        FOREIGN KEY (thread_id) references threads(unique_id) ON DELETE CASCADE,
+        FOREIGN KEY (creator_uid) references users(unique_id) ON DELETE CASCADE
+    );
+
+    -- Hashed single-use tokens sent by email (e.g. for verification)
+    CREATE TABLE IF NOT EXISTS user_tokens (
+        unique_id INTEGER PRIMARY KEY,
+        user_id INTEGER NOT NULL,
+        purpose TEXT NOT NULL,
+        token_hash TEXT UNIQUE NOT NULL,
+        created_at TEXT NOT NULL,
+        expiration TEXT NOT NULL,
+        FOREIGN KEY (user_id) references users(unique_id) ON DELETE CASCADE
+    );
+
+    -- Prior versions of edited threads and comments
+    CREATE TABLE IF NOT EXISTS revisions (
+        unique_id INTEGER PRIMARY KEY,
+        target_type TEXT NOT NULL,
+        target_id INTEGER NOT NULL,
+        version_timestamp TEXT NOT NULL,
+        title TEXT,
+        tag TEXT,
+        content TEXT NOT NULL
+    );
+";
+
+// Columns added to existing tables before migrations existed
+fn add_legacy_columns(conn: &Connection) -> AppResult<()> {
+    add_column_if_missing(conn, "users", "failed_login_count", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "users", "last_failed_login", "TEXT")?;
+    add_column_if_missing(conn, "users", "locked_until", "TEXT")?;
+    add_column_if_missing(conn, "users", "email_verified", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "threads", "edited_at", "TEXT")?;
+    add_column_if_missing(conn, "comments", "edited_at", "TEXT")?;
+    add_column_if_missing(conn, "authentication_keys", "created_at", "TEXT")?;
+    add_column_if_missing(conn, "authentication_keys", "last_used", "TEXT")?;
+    add_column_if_missing(conn, "authentication_keys", "user_agent", "TEXT")?;
+    add_column_if_missing(conn, "authentication_keys", "ip_address", "TEXT")?;
+    add_column_if_missing(conn, "authentication_keys", "key_prefix", "TEXT")?;
+
+    Ok(())
+}
+
+// Salts live inside the encoded password hashes, so the separate (NOT NULL) salt column goes
+fn drop_password_salt(conn: &Connection) -> AppResult<()> {
+    if column_exists(conn, "users", "password_salt")? {
+        conn.execute("ALTER TABLE users DROP COLUMN password_salt", [])?;
+    }
+
+    Ok(())
+}
+
+// The old moderator privilege became the TA role, and each role is only granted once
+const STAFF_ROLES: &str = "
+    UPDATE user_privileges SET privilege = 'ta' WHERE privilege = 'moderator';
+
+    DELETE FROM user_privileges WHERE privilege_unique_id NOT IN
+        (SELECT MIN(privilege_unique_id) FROM user_privileges GROUP BY user_id, privilege);
+
+    CREATE UNIQUE INDEX IF NOT EXISTS user_privileges_user_privilege ON user_privileges (user_id, privilege);
+";
+
+// Authentication keys stored in plaintext are replaced with their digests (sessions stay valid)
+fn hash_plaintext_keys(conn: &Connection) -> AppResult<()> {
+    // Keys stored before hashing was introduced have no prefix recorded
+    let mut plaintext_keys_query = conn.prepare(
+        "SELECT unique_id, authentication_key FROM authentication_keys WHERE key_prefix IS NULL"
+    )?;
+    let plaintext_keys = plaintext_keys_query.query_map([], |row| {
+        let unique_id: isize = row.get(0)?;
+        let authentication_key: String = row.get(1)?;
+        Ok((unique_id, authentication_key))
+    })?;
+
+    let mut legacy_keys: Vec<(isize, String)> = Vec::new();
+    for entry in plaintext_keys {
+        legacy_keys.push(entry?);
+    }
+
+    for (unique_id, authentication_key) in &legacy_keys {
+        conn.execute(
+            "UPDATE authentication_keys SET authentication_key = ?1, key_prefix = ?2 WHERE unique_id = ?3",
+            params![keys::hash_key(authentication_key), keys::key_prefix(authentication_key), unique_id]
+        )?;
+    }
+
+    if !legacy_keys.is_empty() {
+        println!("Hashed {} authentication key(s) that were stored in plaintext", legacy_keys.len());
+    }
+
+    Ok(())
+}
+
+// Indexes for the queries behind every request: key lookups, a user's sessions, a thread's
+// comments and threads by tag. Keys are looked up on key_prefix rather than authentication_key,
+// which only holds a digest since migration 5 and so can't be searched by the presented key.
+// (Migration 9 replaces the threads by tag index with one on thread_tags.)
+const HOT_PATH_INDEXES: &str = "
+    CREATE INDEX IF NOT EXISTS authentication_keys_key_prefix ON authentication_keys (key_prefix);
+
+    CREATE INDEX IF NOT EXISTS authentication_keys_user_id ON authentication_keys (user_id);
+
+    CREATE INDEX IF NOT EXISTS comments_thread_id_creation_timestamp ON comments (thread_id, creation_timestamp);
+
+    CREATE INDEX IF NOT EXISTS threads_tag_creation_timestamp ON threads (tag, creation_timestamp);
+";
+
+// Upvotes, and the columns (and indexes) listings are sorted and paginated by
+const LISTING_SORT_KEYS: &str = "
+    CREATE TABLE votes (
+        unique_id INTEGER PRIMARY KEY,
+        target_type TEXT NOT NULL,
+        target_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        UNIQUE (target_type, target_id, user_id),
+        FOREIGN KEY (user_id) references users(unique_id) ON DELETE CASCADE
+    );
+
+    ALTER TABLE threads ADD COLUMN upvotes INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE threads ADD COLUMN last_activity_at TEXT;
+    ALTER TABLE comments ADD COLUMN upvotes INTEGER NOT NULL DEFAULT 0;
+
+    UPDATE threads SET last_activity_at = MAX(
+        COALESCE(creation_timestamp, ''),
+        COALESCE((SELECT MAX(creation_timestamp) FROM comments WHERE comments.thread_id = threads.unique_id), '')
+    );
+
+    CREATE INDEX threads_creation_timestamp ON threads (creation_timestamp);
+    CREATE INDEX threads_last_activity_at ON threads (last_activity_at);
+    CREATE INDEX threads_upvotes ON threads (upvotes);
+    CREATE INDEX comments_thread_id_upvotes ON comments (thread_id, upvotes);
+";
+
+// Full-text index over threads and comments, kept in sync by triggers. Rows get predictable rowids
+// (even for threads, odd for comments) so that triggers can find them without scanning the index.
+// Comments are indexed with an empty title and tag; their thread's tag is looked up when filtering.
+const SEARCH_INDEX: &str = "
+    CREATE VIRTUAL TABLE search_index USING fts5(
+        title,
+        tag,
+        content,
+        kind UNINDEXED,
+        target_id UNINDEXED,
+        thread_id UNINDEXED,
+        tokenize = 'porter unicode61'
+    );
+
+    INSERT INTO search_index (rowid, title, tag, content, kind, target_id, thread_id)
+        SELECT unique_id * 2, title, tag, content, 'thread', unique_id, unique_id FROM threads;
+    INSERT INTO search_index (rowid, title, tag, content, kind, target_id, thread_id)
+        SELECT unique_id * 2 + 1, '', '', content, 'comment', unique_id, thread_id FROM comments;
+
+    CREATE TRIGGER threads_search_insert AFTER INSERT ON threads BEGIN
+        INSERT INTO search_index (rowid, title, tag, content, kind, target_id, thread_id)
+            VALUES (new.unique_id * 2, new.title, new.tag, new.content, 'thread', new.unique_id, new.unique_id);
+    END;
+    CREATE TRIGGER threads_search_update AFTER UPDATE OF title, tag, content ON threads BEGIN
+        UPDATE search_index SET title = new.title, tag = new.tag, content = new.content WHERE rowid = new.unique_id * 2;
+    END;
+    CREATE TRIGGER threads_search_delete AFTER DELETE ON threads BEGIN
+        DELETE FROM search_index WHERE rowid = old.unique_id * 2;
+    END;
+
+    CREATE TRIGGER comments_search_insert AFTER INSERT ON comments BEGIN
+        INSERT INTO search_index (rowid, title, tag, content, kind, target_id, thread_id)
+            VALUES (new.unique_id * 2 + 1, '', '', new.content, 'comment', new.unique_id, new.thread_id);
+    END;
+    CREATE TRIGGER comments_search_update AFTER UPDATE OF content ON comments BEGIN
+        UPDATE search_index SET content = new.content WHERE rowid = new.unique_id * 2 + 1;
+    END;
+    CREATE TRIGGER comments_search_delete AFTER DELETE ON comments BEGIN
+        DELETE FROM search_index WHERE rowid = old.unique_id * 2 + 1;
+    END;
+";
+
+// Tags become rows of their own, and threads can have several. threads.tag is kept as a
+// space-separated list of the thread's canonical tag names, which search and revisions read.
+const TAGS: &str = "
+    CREATE TABLE tags (
+        unique_id INTEGER PRIMARY KEY,
+        name TEXT UNIQUE NOT NULL,
+        description TEXT,
+        color TEXT,
+        predefined INTEGER NOT NULL DEFAULT 0,
+        created_at TEXT
+    );
+    CREATE TABLE thread_tags (
+        thread_id INTEGER NOT NULL,
+        tag_id INTEGER NOT NULL,
+        PRIMARY KEY (thread_id, tag_id),
+        FOREIGN KEY (thread_id) REFERENCES threads(unique_id) ON DELETE CASCADE,
+        FOREIGN KEY (tag_id) REFERENCES tags(unique_id) ON DELETE CASCADE
+    );
+    CREATE INDEX thread_tags_tag_id ON thread_tags (tag_id, thread_id);
+    DROP INDEX IF EXISTS threads_tag_creation_timestamp;
+";
+
+fn create_tags(conn: &Connection) -> AppResult<()> {
+    conn.execute_batch(TAGS)?;
+
+    // Existing free-form tags are merged by canonical name ("HW1" and "hw1" become one tag)
+    let mut threads_query = conn.prepare("SELECT unique_id, tag FROM threads")?;
+    let threads = threads_query.query_map([], |row| {
+        let unique_id: isize = row.get(0)?;
+        let tag: Option<String> = row.get(1)?;
+        Ok((unique_id, tag))
+    })?;
+
+    let mut tagged_threads: Vec<(isize, String)> = Vec::new();
+    for entry in threads {
+        let (unique_id, tag) = entry?;
+        tagged_threads.push((unique_id, validation::normalize_tag(&tag.unwrap_or_default())));
+    }
+
+    let now = Utc::now().to_rfc3339();
+    for (unique_id, tag) in &tagged_threads {
+        if !tag.is_empty() {
+            conn.execute("INSERT OR IGNORE INTO tags (name, created_at) VALUES (?1, ?2)", params![tag, now])?;
+            conn.execute(
+                "INSERT INTO thread_tags (thread_id, tag_id) SELECT ?1, unique_id FROM tags WHERE name = ?2",
+                params![unique_id, tag]
+            )?;
+        }
+        conn.execute("UPDATE threads SET tag = ?1 WHERE unique_id = ?2", params![tag, unique_id])?;
+    }
+
+    Ok(())
+}
+
+// Names shown next to a user's posts (their username when they haven't picked one)
+const DISPLAY_NAMES: &str = "
+    ALTER TABLE users ADD COLUMN display_name TEXT;
+";
+
+// The characters search uses to mark matches (search::MATCH_START and MATCH_END) are left out of
+// the index, so that posts containing them can't pass off their own text as a highlighted match
+const SEARCH_WITHOUT_MARKERS: &str = "
+    DROP TRIGGER threads_search_insert;
+    DROP TRIGGER threads_search_update;
+    DROP TRIGGER comments_search_insert;
+    DROP TRIGGER comments_search_update;
+
+    UPDATE search_index SET title = replace(replace(title, char(1), ''), char(2), ''), content = replace(replace(content, char(1), ''), char(2), '');
+
+    CREATE TRIGGER threads_search_insert AFTER INSERT ON threads BEGIN
+        INSERT INTO search_index (rowid, title, tag, content, kind, target_id, thread_id)
+            VALUES (new.unique_id * 2, replace(replace(new.title, char(1), ''), char(2), ''), new.tag, replace(replace(new.content, char(1), ''), char(2), ''), 'thread', new.unique_id, new.unique_id);
+    END;
+    CREATE TRIGGER threads_search_update AFTER UPDATE OF title, tag, content ON threads BEGIN
+        UPDATE search_index SET title = replace(replace(new.title, char(1), ''), char(2), ''), tag = new.tag, content = replace(replace(new.content, char(1), ''), char(2), '') WHERE rowid = new.unique_id * 2;
+    END;
+    CREATE TRIGGER comments_search_insert AFTER INSERT ON comments BEGIN
+        INSERT INTO search_index (rowid, title, tag, content, kind, target_id, thread_id)
+            VALUES (new.unique_id * 2 + 1, '', '', replace(replace(new.content, char(1), ''), char(2), ''), 'comment', new.unique_id, new.thread_id);
+    END;
+    CREATE TRIGGER comments_search_update AFTER UPDATE OF content ON comments BEGIN
+        UPDATE search_index SET content = replace(replace(new.content, char(1), ''), char(2), '') WHERE rowid = new.unique_id * 2 + 1;
+    END;
+";
+
+// Helpers
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> AppResult<bool> {
+    // Look through the table's existing columns
+    let mut table_info_query = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let column_names = table_info_query.query_map([], |row| {
+        let name: String = row.get(1)?;
+        Ok(name)
+    })?;
+
+    for entry in column_names {
+        if entry? == column {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> AppResult<()> {
+    // Add the column if it wasn't found
+    if !column_exists(conn, table, column)? {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+
+    Ok(())
+}
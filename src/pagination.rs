@@ -0,0 +1,110 @@
+use rusqlite::types::Value;
+use crate::errors::{AppError, AppResult};
+
+// Listing order
+//
+// Every order is keyset-paginated on (sort column, unique_id): each page ends with an opaque cursor
+// holding the last row's key, and the next page starts right after it. Unlike offsets, pages stay
+// consistent while rows are being added, and deep pages cost no more than the first one.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SortOrder {
+    Newest,
+    Oldest,
+    Active,
+    Upvoted,
+}
+
+impl SortOrder {
+    pub fn parse(value: &str, allowed: &[SortOrder]) -> AppResult<SortOrder> {
+        match allowed.iter().find(|sort| sort.name() == value) {
+            Some(sort) => Ok(*sort),
+            None => {
+                let names: Vec<&str> = allowed.iter().map(|sort| sort.name()).collect();
+                Err(AppError::Validation(format!("sort must be one of: {}", names.join(", "))))
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortOrder::Newest => "newest",
+            SortOrder::Oldest => "oldest",
+            SortOrder::Active => "active",
+            SortOrder::Upvoted => "upvoted",
+        }
+    }
+
+    // The column rows are ordered by (ties are broken by unique_id in the same direction)
+    pub fn column(&self) -> &'static str {
+        match self {
+            SortOrder::Newest | SortOrder::Oldest => "creation_timestamp",
+            SortOrder::Active => "last_activity_at",
+            SortOrder::Upvoted => "upvotes",
+        }
+    }
+
+    pub fn descending(&self) -> bool {
+        *self != SortOrder::Oldest
+    }
+
+    // ORDER BY clause, and the WHERE condition that starts a page after the cursor position
+    // (bound to ?1 and ?2)
+    pub fn order_by(&self) -> String {
+        let direction = if self.descending() { "DESC" } else { "ASC" };
+        format!("{} {}, unique_id {}", self.column(), direction, direction)
+    }
+
+    pub fn after_cursor(&self) -> String {
+        let comparison = if self.descending() { "<" } else { ">" };
+        format!("({}, unique_id) {} (?1, ?2)", self.column(), comparison)
+    }
+}
+
+// The position of the last row on a page
+pub struct Cursor {
+    pub key: Value,
+    pub unique_id: i64,
+}
+
+impl Cursor {
+    // Cursors are only meaningful for the order they were issued for
+    pub fn encode(&self, sort: SortOrder) -> String {
+        let key = match &self.key {
+            Value::Integer(val) => val.to_string(),
+            Value::Text(val) => val.clone(),
+            _ => String::new(),
+        };
+        base64::encode_config(format!("{}|{}|{}", sort.name(), self.unique_id, key), base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(cursor: &str, sort: SortOrder) -> AppResult<Cursor> {
+        let invalid = || AppError::Validation(String::from("cursor is invalid (cursors can't be reused with a different sort)"));
+
+        let decoded = match base64::decode_config(cursor, base64::URL_SAFE_NO_PAD) {
+            Ok(val) => String::from_utf8(val).map_err(|_| invalid())?,
+            Err(_) => return Err(invalid()),
+        };
+        let mut parts = decoded.splitn(3, '|');
+        let (name, unique_id, key) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(unique_id), Some(key)) => (name, unique_id, key),
+            _ => return Err(invalid()),
+        };
+        if name != sort.name() {
+            return Err(invalid());
+        }
+
+        let unique_id: i64 = unique_id.parse().map_err(|_| invalid())?;
+        let key = match sort {
+            SortOrder::Upvoted => Value::Integer(key.parse().map_err(|_| invalid())?),
+            _ => Value::Text(key.to_string()),
+        };
+
+        Ok(Cursor { key, unique_id })
+    }
+}
+
+// A page of results, with the cursor for the next one (None on the last page)
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
@@ -0,0 +1,126 @@
+use std::marker::PhantomData;
+use rocket::Request;
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::outcome::Outcome as GuardOutcome;
+use rocket::request::{FromRequest, Outcome};
+use crate::{app_logic, db};
+use crate::app_logic::CurrentUser;
+use crate::config::AppConfig;
+use crate::db::DbPool;
+
+// Roles
+//
+// Granted roles are stored in the user_privileges table. Every user is a student, so only
+// the elevated roles are ever stored.
+pub const STUDENT: &str = "student";
+pub const TA: &str = "ta";
+pub const INSTRUCTOR: &str = "instructor";
+pub const ADMIN: &str = "admin";
+
+pub const GRANTABLE_ROLES: &[&str] = &[TA, INSTRUCTOR, ADMIN];
+
+// A permission, granted by any of the listed roles
+pub trait Permission {
+    const ROLES: &'static [&'static str];
+}
+
+// Deleting other users' threads and comments
+pub struct ModerateContent;
+
+impl Permission for ModerateContent {
+    const ROLES: &'static [&'static str] = &[TA, INSTRUCTOR, ADMIN];
+}
+
+// Creating, describing & deleting the course's tags
+pub struct ManageTags;
+
+impl Permission for ManageTags {
+    const ROLES: &'static [&'static str] = &[INSTRUCTOR, ADMIN];
+}
+
+// Granting & revoking roles, and lifting lockouts
+pub struct ManageUsers;
+
+impl Permission for ManageUsers {
+    const ROLES: &'static [&'static str] = &[ADMIN];
+}
+
+// Request guard for routes that need a permission (fails with 401 without a valid key, and
+// with 403 when none of the user's roles grant the permission)
+pub struct RequirePrivilege<P: Permission> {
+    pub unique_user_id: String,
+    _permission: PhantomData<P>,
+}
+
+#[rocket::async_trait]
+impl<'r, P: Permission> FromRequest<'r> for RequirePrivilege<P> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        // The user must be signed in first (resolved once per request, roles included)
+        let user = match request.guard::<&CurrentUser>().await {
+            GuardOutcome::Success(val) => val,
+            GuardOutcome::Failure((status, _)) => return Outcome::Failure((status, ())),
+            GuardOutcome::Forward(val) => return Outcome::Forward(val),
+        };
+
+        // Check whether any of their roles grant the permission
+        if user.has_permission::<P>() {
+            Outcome::Success(RequirePrivilege { unique_user_id: user.unique_id.clone(), _permission: PhantomData })
+        } else {
+            Outcome::Failure((Status::Forbidden, ()))
+        }
+    }
+}
+
+// Fairing that makes the configured bootstrap users admins (so that a fresh install has someone
+// who can grant roles)
+pub fn init_admins() -> AdHoc {
+    AdHoc::try_on_ignite("Bootstrap Admins", |rocket| async move {
+        let usernames = match rocket.state::<AppConfig>() {
+            Some(val) => val.auth.admin_usernames.clone(),
+            None => {
+                println!("Bootstrapping admins requires the application config to be attached first");
+                return Err(rocket);
+            }
+        };
+        if usernames.is_empty() {
+            return Ok(rocket);
+        }
+
+        let result = match rocket.state::<DbPool>() {
+            Some(db_pool) => db::get_connection(db_pool).and_then(|mut conn| {
+                for username in &usernames {
+                    // Only accounts that already exist and have verified their email address are
+                    // promoted, so a listed name nobody has registered yet isn't up for grabs
+                    let unique_user_id = match app_logic::get_uid_from_username(&mut conn, username) {
+                        Ok(val) => val,
+                        Err(_) => {
+                            rocket::warn!("Not making '{}' an admin: no such user", username);
+                            continue;
+                        }
+                    };
+                    if !app_logic::is_email_verified(&mut conn, &unique_user_id)? {
+                        rocket::warn!("Not making '{}' an admin: email address isn't verified", username);
+                        continue;
+                    }
+                    app_logic::grant_role(&mut conn, &unique_user_id, ADMIN)?;
+                }
+                Ok(())
+            }),
+            None => {
+                println!("Bootstrapping admins requires the database pool to be attached first");
+                return Err(rocket);
+            }
+        };
+
+        match result {
+            Ok(_) => Ok(rocket),
+            Err(e) => {
+                println!("Unable to bootstrap admins: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
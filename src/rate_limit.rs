@@ -0,0 +1,117 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::errors::{AppError, AppResult};
+
+// Sliding window rate limiter
+//
+// Remembers when each key (e.g. "login-ip:1.2.3.4") was last used and rejects the request once
+// the key has been used `max_requests` times within the trailing window. Keys come from requests
+// (usernames included), so keys that have gone quiet are swept out once per window, and no more
+// than `max_keys` are tracked at once.
+pub struct RateLimiter {
+    windows: Mutex<HashMap<String, VecDeque<Instant>>>,
+    last_sweep: Mutex<Instant>,
+    max_keys: usize,
+}
+
+const MAX_KEYS: usize = 100_000;
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter::with_max_keys(MAX_KEYS)
+    }
+
+    pub fn with_max_keys(max_keys: usize) -> Self {
+        RateLimiter {
+            windows: Mutex::new(HashMap::new()),
+            last_sweep: Mutex::new(Instant::now()),
+            max_keys,
+        }
+    }
+
+    // Records a request against the key, failing with the time until a slot frees up
+    pub fn check(&self, key: &str, max_requests: u32, window: Duration) -> AppResult<()> {
+        let now = Instant::now();
+        let mut windows = match self.windows.lock() {
+            Ok(val) => val,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        // Forget keys that have gone quiet, and make room for a new key if the map is full
+        let mut last_sweep = match self.last_sweep.lock() {
+            Ok(val) => val,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if now.duration_since(*last_sweep) >= window {
+            sweep(&mut windows, now, window);
+            *last_sweep = now;
+        }
+        if windows.len() >= self.max_keys && !windows.contains_key(key) {
+            make_room(&mut windows, self.max_keys, now, window);
+        }
+
+        // Forget requests that have slid out of the window
+        let timestamps = windows.entry(key.to_string()).or_insert_with(VecDeque::new);
+        while let Some(oldest) = timestamps.front() {
+            if now.duration_since(*oldest) >= window {
+                timestamps.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if timestamps.len() >= max_requests as usize {
+            let retry_after = match timestamps.front() {
+                Some(oldest) => window.saturating_sub(now.duration_since(*oldest)),
+                None => window,
+            };
+            return Err(AppError::RateLimited(
+                String::from("Too many requests, please try again later"),
+                retry_after.as_secs().max(1)
+            ));
+        }
+
+        timestamps.push_back(now);
+        Ok(())
+    }
+
+    // Clears the history of a key (e.g. when an admin lifts a lockout)
+    pub fn reset(&self, key: &str) {
+        let mut windows = match self.windows.lock() {
+            Ok(val) => val,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        windows.remove(key);
+    }
+
+    #[cfg(test)]
+    pub fn tracked_keys(&self) -> usize {
+        self.windows.lock().map(|windows| windows.len()).unwrap_or(0)
+    }
+}
+
+// Drops every key whose latest request has slid out of the window
+fn sweep(windows: &mut HashMap<String, VecDeque<Instant>>, now: Instant, window: Duration) {
+    windows.retain(|_, timestamps| match timestamps.back() {
+        Some(newest) => now.duration_since(*newest) < window,
+        None => false,
+    });
+}
+
+// Brings the map below max_keys, forgetting the least recently used half if sweeping isn't enough
+// (which only happens when more keys than that are in active use within a single window)
+fn make_room(windows: &mut HashMap<String, VecDeque<Instant>>, max_keys: usize, now: Instant, window: Duration) {
+    sweep(windows, now, window);
+    if windows.len() < max_keys {
+        return;
+    }
+
+    let mut last_used: Vec<Instant> = windows.values().filter_map(|timestamps| timestamps.back().copied()).collect();
+    last_used.sort_unstable();
+    let cutoff = last_used[last_used.len() / 2];
+    windows.retain(|_, timestamps| match timestamps.back() {
+        Some(newest) => *newest > cutoff,
+        None => false,
+    });
+}
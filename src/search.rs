@@ -0,0 +1,71 @@
+// Full-text search
+//
+// Search input is never handed to FTS5 as-is (its query syntax has operators, column filters and
+// plenty of ways to be malformed). Instead it is rebuilt from the parts we support: words, which
+// all have to match, "quoted phrases", and a trailing * for prefix matching.
+
+// Markers SQLite puts around matches. They are control characters that the search index never
+// contains (migration 11 strips them as posts are indexed), so any in a result came from SQLite.
+pub const MATCH_START: &str = "\u{1}";
+pub const MATCH_END: &str = "\u{2}";
+
+// Builds an FTS5 query out of search input (None when there is nothing to search for)
+pub fn fts_query(input: &str) -> Option<String> {
+    let mut terms: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut in_phrase = false;
+
+    for c in input.chars().chain(std::iter::once(' ')) {
+        match c {
+            '"' => {
+                push_term(&mut terms, &current, in_phrase);
+                current.clear();
+                in_phrase = !in_phrase;
+            },
+            c if c.is_whitespace() && !in_phrase => {
+                push_term(&mut terms, &current, false);
+                current.clear();
+            },
+            c => current.push(c),
+        }
+    }
+
+    // An unclosed quote still counts as a phrase
+    push_term(&mut terms, &current, in_phrase);
+
+    match terms.is_empty() {
+        true => None,
+        false => Some(terms.join(" ")),
+    }
+}
+
+fn push_term(terms: &mut Vec<String>, term: &str, phrase: bool) {
+    let term = term.trim();
+    let prefix = !phrase && term.ends_with('*');
+    let term = term.trim_end_matches('*');
+
+    // Punctuation on its own wouldn't match anything
+    if !term.chars().any(char::is_alphanumeric) {
+        return;
+    }
+
+    let quoted = format!("\"{}\"", term.replace('"', "\"\""));
+    terms.push(if prefix { format!("{}*", quoted) } else { quoted });
+}
+
+// Escapes text for HTML
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// Escapes text that came out of the search index for HTML, then turns the match markers into
+// <mark> tags
+pub fn highlight_html(text: &str) -> String {
+    escape_html(text)
+        .replace(MATCH_START, "<mark>")
+        .replace(MATCH_END, "</mark>")
+}
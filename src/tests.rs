@@ -0,0 +1,2068 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use rocket::error::ErrorKind;
+use rocket::figment::Figment;
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::asynchronous::Client as AsyncClient;
+use rocket::local::blocking::{Client, LocalResponse};
+use rocket::serde::json::{Value, serde_json::json};
+use rusqlite::params;
+use rusqlite::types::Value as SqlValue;
+use super::build_rocket;
+use crate::app_logic::{self, ThreadFilters};
+use crate::config::{AppConfig, MailConfig};
+use crate::db::{self, DbConnection, DbPool};
+use crate::diff::line_diff;
+use crate::keys;
+use crate::mailer::{Email, MailTransport, Mailer, SmtpMailer};
+use crate::migrations;
+use crate::pagination::{Cursor, SortOrder};
+use crate::privileges;
+use crate::rate_limit::RateLimiter;
+use crate::search;
+
+// Helpers
+fn test_figment() -> Figment {
+    // Every client gets its own isolated in-memory database and maildir (and cheap password hashing)
+    let maildir = std::env::temp_dir().join(format!("halp-test-mail-{}", keys::generate_key(16)));
+    rocket::Config::figment()
+        .merge(("database.in_memory", true))
+        .merge(("mail.maildir", maildir.to_string_lossy().to_string()))
+        .merge(("password_hashing.mem_cost", 1024))
+        .merge(("password_hashing.time_cost", 1))
+        .merge(("log_level", "off"))
+}
+
+fn client() -> Client {
+    Client::tracked(build_rocket(test_figment())).expect("valid rocket instance")
+}
+
+fn db_conn(client: &Client) -> DbConnection {
+    let pool = client.rocket().state::<DbPool>().expect("managed pool");
+    db::get_connection(pool).expect("pooled connection")
+}
+
+fn json_body(response: LocalResponse<'_>) -> Value {
+    response.into_json::<Value>().expect("JSON response body")
+}
+
+fn post_json<'c>(client: &'c Client, uri: &'c str, key: Option<&str>, body: Value) -> LocalResponse<'c> {
+    let mut request = client.post(uri).header(ContentType::JSON).body(body.to_string());
+    if let Some(key) = key {
+        request = request.header(Header::new("x-auth-key", key.to_string()));
+    }
+    request.dispatch()
+}
+
+fn patch_json<'c>(client: &'c Client, uri: &'c str, key: &str, body: Value) -> LocalResponse<'c> {
+    client.patch(uri)
+        .header(ContentType::JSON)
+        .header(Header::new("x-auth-key", key.to_string()))
+        .body(body.to_string())
+        .dispatch()
+}
+
+fn get_with_key<'c>(client: &'c Client, uri: &'c str, key: &str) -> LocalResponse<'c> {
+    client.get(uri).header(Header::new("x-auth-key", key.to_string())).dispatch()
+}
+
+fn delete_with_key<'c>(client: &'c Client, uri: &'c str, key: &str) -> LocalResponse<'c> {
+    client.delete(uri).header(Header::new("x-auth-key", key.to_string())).dispatch()
+}
+
+fn register(client: &Client, username: &str) {
+    let response = post_json(client, "/register", None, json!({
+        "username": username,
+        "email": format!("{}@example.com", username),
+        "password": "correct horse battery staple",
+    }));
+    assert_eq!(response.status(), Status::Ok);
+}
+
+fn login(client: &Client, username: &str) -> String {
+    let response = post_json(client, "/login", None, json!({
+        "username": username,
+        "password": "correct horse battery staple",
+    }));
+    assert_eq!(response.status(), Status::Ok);
+    json_body(response)["auth_key"].as_str().expect("auth key").to_string()
+}
+
+fn register_and_login(client: &Client, username: &str) -> String {
+    register(client, username);
+    login(client, username)
+}
+
+fn create_thread(client: &Client, key: &str, title: &str) {
+    let response = post_json(client, "/thread/create", Some(key), json!({
+        "title": title,
+        "tag": "hw1",
+        "content": "How do I start?",
+    }));
+    assert_eq!(response.status(), Status::Ok);
+}
+
+fn create_comment(client: &Client, key: &str, thread_id: i64) {
+    let uri = format!("/thread/{}/create_comment", thread_id);
+    let response = client.post(uri)
+        .header(ContentType::JSON)
+        .header(Header::new("x-auth-key", key.to_string()))
+        .body(json!({"content": "Read the handout"}).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+fn threads(client: &Client, key: &str) -> Vec<Value> {
+    let response = get_with_key(client, "/threads", key);
+    assert_eq!(response.status(), Status::Ok);
+    json_body(response)["threads"].as_array().expect("threads array").clone()
+}
+
+fn comments(client: &Client, key: &str, thread_id: i64) -> Vec<Value> {
+    let uri = format!("/threads/{}/comments", thread_id);
+    let response = client.get(uri).header(Header::new("x-auth-key", key.to_string())).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    json_body(response)["comments"].as_array().expect("comments array").clone()
+}
+
+fn grant(client: &Client, username: &str, role: &str) {
+    let mut conn = db_conn(client);
+    let unique_user_id = app_logic::get_uid_from_username(&mut conn, &username.to_string()).unwrap();
+    app_logic::grant_role(&mut conn, &unique_user_id, role).unwrap();
+}
+
+fn assert_error(response: LocalResponse<'_>, status: Status, code: &str) {
+    assert_eq!(response.status(), status);
+    assert_eq!(json_body(response)["error"]["code"], code);
+}
+
+// Index
+#[test]
+fn index_responds() {
+    let client = client();
+    let response = client.get("/").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().unwrap(), "Hello, world!");
+}
+
+#[test]
+fn unknown_route_returns_json_error() {
+    let client = client();
+    assert_error(client.get("/does/not/exist").dispatch(), Status::NotFound, "not_found");
+}
+
+// Configuration
+#[test]
+fn default_config_is_valid() {
+    assert!(AppConfig::default().validate().is_empty());
+}
+
+#[test]
+fn invalid_config_is_reported() {
+    let mut config = AppConfig::default();
+    config.database.pool_size = 0;
+    config.auth.token_ttl_hours = 0;
+    config.password_hashing.variant = String::from("md5");
+    config.cors.allowed_origins = vec![String::from("example.com")];
+    assert_eq!(config.validate().len(), 4);
+}
+
+#[test]
+fn invalid_config_aborts_launch() {
+    let figment = test_figment().merge(("database.pool_size", 0));
+    match Client::tracked(build_rocket(figment)) {
+        Ok(_) => panic!("launch should have been aborted"),
+        Err(e) => assert!(matches!(e.kind(), ErrorKind::FailedFairings(_))),
+    }
+}
+
+#[test]
+fn configured_key_length_is_used() {
+    let figment = test_figment().merge(("auth.key_length", 48));
+    let client = Client::tracked(build_rocket(figment)).unwrap();
+    assert_eq!(register_and_login(&client, "alice").len(), 48);
+}
+
+#[test]
+fn content_limits_are_enforced() {
+    let figment = test_figment().merge(("content.max_title_length", 5));
+    let client = Client::tracked(build_rocket(figment)).unwrap();
+    let key = register_and_login(&client, "alice");
+
+    let response = post_json(&client, "/thread/create", Some(&key), json!({
+        "title": "Far too long",
+        "tag": "hw1",
+        "content": "c",
+    }));
+    assert_error(response, Status::UnprocessableEntity, "validation_failed");
+
+    let response = post_json(&client, "/thread/create", Some(&key), json!({
+        "title": "Short",
+        "tag": "hw1",
+        "content": " ",
+    }));
+    assert_error(response, Status::UnprocessableEntity, "validation_failed");
+}
+
+// CORS
+fn cors_client(allowed_origins: Vec<&str>) -> Client {
+    let figment = test_figment().merge(("cors.allowed_origins", allowed_origins));
+    Client::tracked(build_rocket(figment)).unwrap()
+}
+
+#[test]
+fn preflight_lists_route_methods() {
+    let client = cors_client(vec!["https://halp.example"]);
+    let response = client.options("/thread/7")
+        .header(Header::new("Origin", "https://halp.example"))
+        .header(Header::new("Access-Control-Request-Method", "DELETE"))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::NoContent);
+    let headers = response.headers();
+    assert_eq!(headers.get_one("Access-Control-Allow-Origin"), Some("https://halp.example"));
+    assert_eq!(headers.get_one("Access-Control-Allow-Methods"), Some("DELETE, OPTIONS, PATCH"));
+    assert_eq!(headers.get_one("Access-Control-Allow-Headers"), Some("Content-Type, x-auth-key"));
+    assert_eq!(headers.get_one("Access-Control-Allow-Credentials"), Some("true"));
+    assert_eq!(headers.get_one("Vary"), Some("Origin"));
+}
+
+#[test]
+fn preflight_rejects_unknown_paths_and_methods() {
+    let client = cors_client(vec!["https://halp.example"]);
+    let response = client.options("/nothing/here")
+        .header(Header::new("Origin", "https://halp.example"))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = client.options("/threads")
+        .header(Header::new("Origin", "https://halp.example"))
+        .header(Header::new("Access-Control-Request-Method", "DELETE"))
+        .dispatch();
+    assert_eq!(response.status(), Status::MethodNotAllowed);
+}
+
+#[test]
+fn disallowed_origin_gets_no_cors_headers() {
+    let client = cors_client(vec!["https://halp.example"]);
+    let response = client.get("/").header(Header::new("Origin", "https://evil.example")).dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+    assert_eq!(response.headers().get_one("Vary"), Some("Origin"));
+}
+
+#[test]
+fn wildcard_allowlist_sends_no_credentials() {
+    let figment = test_figment()
+        .merge(("cors.allowed_origins", vec!["*"]))
+        .merge(("cors.allow_credentials", false));
+    let client = Client::tracked(build_rocket(figment)).unwrap();
+    let response = client.get("/").header(Header::new("Origin", "https://anywhere.example")).dispatch();
+    assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), Some("*"));
+    assert_eq!(response.headers().get_one("Access-Control-Allow-Credentials"), None);
+}
+
+#[test]
+fn credentialed_wildcard_is_refused() {
+    let mut config = AppConfig::default();
+    config.cors.allowed_origins = vec![String::from("*")];
+    assert_eq!(config.validate().len(), 1);
+    config.cors.allow_credentials = false;
+    assert!(config.validate().is_empty());
+}
+
+#[test]
+fn no_origins_are_allowed_by_default() {
+    let client = client();
+    let response = client.get("/").header(Header::new("Origin", "https://anywhere.example")).dispatch();
+    assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+}
+
+// Concurrency
+#[test]
+fn concurrent_requests_share_the_in_memory_database() {
+    // Registrations (which write inside IMMEDIATE transactions) and logins (which only read) all at
+    // once, enough of them that some are bound to overlap
+    let runtime = rocket::tokio::runtime::Builder::new_multi_thread().worker_threads(4).enable_all().build().unwrap();
+    runtime.block_on(async {
+        let figment = test_figment()
+            .merge(("rate_limit.register_per_ip", 1000))
+            .merge(("rate_limit.login_per_ip", 1000));
+        let client = Arc::new(AsyncClient::tracked(build_rocket(figment)).await.unwrap());
+        let mut requests = Vec::new();
+        for i in 0..200 {
+            let client = client.clone();
+            requests.push(rocket::tokio::spawn(async move {
+                let (uri, body) = match i % 2 {
+                    0 => ("/register", json!({"username": format!("user{}", i), "email": format!("user{}@example.com", i), "password": "correct horse battery staple"})),
+                    _ => ("/login", json!({"username": format!("nobody{}", i), "password": "correct horse battery staple"})),
+                };
+                client.post(uri).header(ContentType::JSON).body(body.to_string()).dispatch().await.status()
+            }));
+        }
+        for (i, request) in requests.into_iter().enumerate() {
+            let expected = if i % 2 == 0 { Status::Ok } else { Status::Unauthorized };
+            assert_eq!(request.await.unwrap(), expected);
+        }
+    });
+}
+
+// Registration & Login
+#[test]
+fn register_succeeds() {
+    let client = client();
+    let response = post_json(&client, "/register", None, json!({
+        "username": "alice",
+        "email": "alice@example.com",
+        "password": "correct horse battery staple",
+    }));
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(json_body(response)["success"], true);
+}
+
+#[test]
+fn register_duplicate_username_conflicts() {
+    let client = client();
+    register(&client, "alice");
+    let response = post_json(&client, "/register", None, json!({
+        "username": "alice",
+        "email": "other@example.com",
+        "password": "correct horse battery staple",
+    }));
+    assert_error(response, Status::Conflict, "conflict");
+}
+
+#[test]
+fn register_rejects_malformed_body() {
+    let client = client();
+    let response = post_json(&client, "/register", None, json!({"username": "alice"}));
+    assert_error(response, Status::UnprocessableEntity, "validation_failed");
+}
+
+fn field_errors(response: LocalResponse<'_>) -> Vec<String> {
+    json_body(response)["error"]["fields"]
+        .as_array()
+        .expect("field errors")
+        .iter()
+        .map(|field| field["field"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn register_reports_every_invalid_field() {
+    let client = client();
+    let response = post_json(&client, "/register", None, json!({
+        "username": "a!",
+        "email": "not-an-email",
+        "password": "short",
+    }));
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(field_errors(response), vec!["username", "email", "password"]);
+}
+
+#[test]
+fn register_rejects_common_passwords() {
+    let client = client();
+    let response = post_json(&client, "/register", None, json!({
+        "username": "alice",
+        "email": "alice@example.com",
+        "password": "Password123",
+    }));
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(field_errors(response), vec!["password"]);
+}
+
+#[test]
+fn password_policy_is_configurable() {
+    let figment = test_figment().merge(("password_policy.min_length", 30));
+    let client = Client::tracked(build_rocket(figment)).unwrap();
+    let response = post_json(&client, "/register", None, json!({
+        "username": "alice",
+        "email": "alice@example.com",
+        "password": "correct horse battery staple",
+    }));
+    assert_eq!(field_errors(response), vec!["password"]);
+}
+
+#[test]
+fn register_normalizes_email_and_ignores_case_for_uniqueness() {
+    let client = client();
+    let response = post_json(&client, "/register", None, json!({
+        "username": "alice",
+        "email": "  Alice@Example.COM ",
+        "password": "correct horse battery staple",
+    }));
+    assert_eq!(response.status(), Status::Ok);
+    let email: String = db_conn(&client)
+        .query_row("SELECT email FROM users WHERE username = 'alice'", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(email, "alice@example.com");
+
+    let response = post_json(&client, "/register", None, json!({
+        "username": "ALICE",
+        "email": "alice@EXAMPLE.com",
+        "password": "correct horse battery staple",
+    }));
+    assert_eq!(response.status(), Status::Conflict);
+    assert_eq!(field_errors(response), vec!["username", "email"]);
+}
+
+#[test]
+fn login_returns_key_and_expiration() {
+    let client = client();
+    register(&client, "alice");
+    let response = post_json(&client, "/login", None, json!({
+        "username": "alice",
+        "password": "correct horse battery staple",
+    }));
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response);
+    assert_eq!(body["auth_key"].as_str().unwrap().len(), 32);
+    assert!(body["expiration_datetime"].is_string());
+}
+
+#[test]
+fn login_unknown_user_is_unauthorized() {
+    let client = client();
+    let response = post_json(&client, "/login", None, json!({
+        "username": "nobody",
+        "password": "whatever",
+    }));
+    assert_error(response, Status::Unauthorized, "unauthorized");
+}
+
+#[test]
+fn wrong_password_gets_same_response_as_unknown_user() {
+    let client = client();
+    register(&client, "alice");
+
+    let wrong_password = post_json(&client, "/login", None, json!({
+        "username": "alice",
+        "password": "not the password",
+    }));
+    assert_eq!(wrong_password.status(), Status::Unauthorized);
+    let wrong_password_body = json_body(wrong_password);
+
+    let unknown_user = post_json(&client, "/login", None, json!({
+        "username": "nobody",
+        "password": "not the password",
+    }));
+    assert_eq!(unknown_user.status(), Status::Unauthorized);
+    assert_eq!(json_body(unknown_user), wrong_password_body);
+
+    // No key was issued for the failed attempt
+    let issued: i64 = db_conn(&client)
+        .query_row("SELECT COUNT(*) FROM authentication_keys", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(issued, 0);
+}
+
+#[test]
+fn failed_logins_are_counted_and_reset() {
+    let client = client();
+    register(&client, "alice");
+    for _ in 0..2 {
+        let response = post_json(&client, "/login", None, json!({"username": "alice", "password": "nope"}));
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    let failed_logins = |client: &Client| -> i64 {
+        db_conn(client)
+            .query_row("SELECT failed_login_count FROM users WHERE username = 'alice'", [], |row| row.get(0))
+            .unwrap()
+    };
+    assert_eq!(failed_logins(&client), 2);
+
+    login(&client, "alice");
+    assert_eq!(failed_logins(&client), 0);
+}
+
+#[test]
+fn login_attempts_are_rate_limited_per_ip() {
+    let figment = test_figment().merge(("rate_limit.login_per_ip", 2));
+    let client = Client::tracked(build_rocket(figment)).unwrap();
+    for _ in 0..2 {
+        let response = post_json(&client, "/login", None, json!({"username": "nobody", "password": "nope"}));
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    let response = post_json(&client, "/login", None, json!({"username": "nobody", "password": "nope"}));
+    assert_eq!(response.status(), Status::TooManyRequests);
+    assert!(response.headers().get_one("Retry-After").is_some());
+    assert_error(response, Status::TooManyRequests, "rate_limited");
+}
+
+fn login_from(client: &Client, remote: &str, real_ip: &str) -> Status {
+    client.post("/login")
+        .remote(remote.parse().unwrap())
+        .header(Header::new("X-Real-IP", real_ip.to_string()))
+        .header(ContentType::JSON)
+        .body(json!({"username": "nobody", "password": "nope"}).to_string())
+        .dispatch()
+        .status()
+}
+
+#[test]
+fn real_ip_header_is_only_believed_from_trusted_proxies() {
+    let figment = test_figment()
+        .merge(("rate_limit.login_per_ip", 2))
+        .merge(("rate_limit.login_per_username", 100))
+        .merge(("rate_limit.trusted_proxies", vec!["10.0.0.1"]));
+    let client = Client::tracked(build_rocket(figment)).unwrap();
+
+    // Clients can't get a fresh limit by making up addresses
+    assert_eq!(login_from(&client, "203.0.113.5:4000", "198.51.100.1"), Status::Unauthorized);
+    assert_eq!(login_from(&client, "203.0.113.5:4000", "198.51.100.2"), Status::Unauthorized);
+    assert_eq!(login_from(&client, "203.0.113.5:4000", "198.51.100.3"), Status::TooManyRequests);
+
+    // The proxy's own address is shared by everyone behind it
+    for ip in &["198.51.100.1", "198.51.100.2", "198.51.100.3"] {
+        assert_eq!(login_from(&client, "10.0.0.1:4000", ip), Status::Unauthorized);
+    }
+}
+
+#[test]
+fn rate_limiter_tracks_a_bounded_number_of_keys() {
+    let limiter = RateLimiter::with_max_keys(10);
+    let window = std::time::Duration::from_secs(60);
+    for i in 0..1000 {
+        limiter.check(&format!("login-user:random{}", i), 5, window).unwrap();
+    }
+    assert!(limiter.tracked_keys() <= 10);
+
+    // Keys that are still being used keep their history
+    for _ in 0..5 {
+        limiter.check("login-user:alice", 5, window).unwrap();
+    }
+    assert!(limiter.check("login-user:alice", 5, window).is_err());
+}
+
+#[test]
+fn registrations_are_rate_limited_per_ip() {
+    let figment = test_figment().merge(("rate_limit.register_per_ip", 1));
+    let client = Client::tracked(build_rocket(figment)).unwrap();
+    register(&client, "alice");
+
+    let response = post_json(&client, "/register", None, json!({
+        "username": "bob",
+        "email": "bob@example.com",
+        "password": "correct horse battery staple",
+    }));
+    assert_error(response, Status::TooManyRequests, "rate_limited");
+}
+
+fn lock_out(client: &Client, username: &str) {
+    for _ in 0..3 {
+        let response = post_json(client, "/login", None, json!({"username": username, "password": "nope"}));
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+}
+
+fn lockout_client() -> Client {
+    let figment = test_figment().merge(("rate_limit.lockout_threshold", 3));
+    Client::tracked(build_rocket(figment)).unwrap()
+}
+
+#[test]
+fn repeated_failed_logins_lock_the_account() {
+    let client = lockout_client();
+    register(&client, "alice");
+    lock_out(&client, "alice");
+
+    // Even the correct password is refused while locked, exactly like an unknown user
+    let locked = post_json(&client, "/login", None, json!({
+        "username": "alice",
+        "password": "correct horse battery staple",
+    }));
+    assert_eq!(locked.status(), Status::Unauthorized);
+    let locked_body = locked.into_string();
+    let unknown = post_json(&client, "/login", None, json!({"username": "nobody", "password": "correct horse battery staple"}));
+    assert_eq!(unknown.status(), Status::Unauthorized);
+    assert_eq!(locked_body, unknown.into_string());
+}
+
+#[test]
+fn every_failed_login_costs_one_hash() {
+    let client = lockout_client();
+    register(&client, "alice");
+    register(&client, "bob");
+    lock_out(&client, "bob");
+
+    // Count the argon2 runs for each kind of failure (login runs on this thread when called directly)
+    let config = client.rocket().state::<AppConfig>().unwrap();
+    let details = app_logic::ClientDetails { user_agent: None, ip_address: None };
+    let mut conn = db_conn(&client);
+    let mut argon2_runs = |username: &str, password: &str| {
+        app_logic::ARGON2_RUNS.with(|runs| runs.set(0));
+        assert!(app_logic::login(&mut conn, &username.to_string(), &password.to_string(), config, &details).is_err());
+        app_logic::ARGON2_RUNS.with(|runs| runs.get())
+    };
+    assert_eq!(argon2_runs("nobody", "correct horse battery staple"), 1);
+    assert_eq!(argon2_runs("alice", "wrong"), 1);
+    assert_eq!(argon2_runs("bob", "correct horse battery staple"), 1);
+}
+
+#[test]
+fn admin_can_clear_lockout() {
+    let client = lockout_client();
+    let admin_key = register_and_login(&client, "admin");
+    grant(&client, "admin", privileges::ADMIN);
+
+    register(&client, "alice");
+    lock_out(&client, "alice");
+
+    let response = delete_with_key(&client, "/admin/users/alice/lockout", &admin_key);
+    assert_eq!(response.status(), Status::Ok);
+    login(&client, "alice");
+}
+
+#[test]
+fn non_admin_cannot_clear_lockout() {
+    let client = lockout_client();
+    let key = register_and_login(&client, "bob");
+    register(&client, "alice");
+    lock_out(&client, "alice");
+
+    assert_error(delete_with_key(&client, "/admin/users/alice/lockout", &key), Status::Forbidden, "forbidden");
+}
+
+// Roles & Privileges
+fn put_with_key<'c>(client: &'c Client, uri: &'c str, key: &str) -> LocalResponse<'c> {
+    client.put(uri).header(Header::new("x-auth-key", key.to_string())).dispatch()
+}
+
+#[test]
+fn admin_grants_and_revokes_roles() {
+    let client = client();
+    let admin = register_and_login(&client, "admin");
+    grant(&client, "admin", privileges::ADMIN);
+    let alice = register_and_login(&client, "alice");
+    let bob = register_and_login(&client, "bob");
+    create_thread(&client, &alice, "First question");
+
+    assert_eq!(put_with_key(&client, "/admin/users/bob/privileges/ta", &admin).status(), Status::Ok);
+    let response = get_with_key(&client, "/admin/users/bob/privileges", &admin);
+    assert_eq!(json_body(response)["privileges"], json!(["student", "ta"]));
+
+    // Revoking the role takes the elevated abilities away again
+    assert_eq!(delete_with_key(&client, "/admin/users/bob/privileges/ta", &admin).status(), Status::Ok);
+    assert_error(delete_with_key(&client, "/thread/1", &bob), Status::Forbidden, "forbidden");
+    assert_error(delete_with_key(&client, "/admin/users/bob/privileges/ta", &admin), Status::NotFound, "not_found");
+}
+
+#[test]
+fn only_admins_manage_roles() {
+    let client = client();
+    let ta = register_and_login(&client, "staff");
+    grant(&client, "staff", privileges::TA);
+    register(&client, "bob");
+
+    assert_error(put_with_key(&client, "/admin/users/bob/privileges/ta", &ta), Status::Forbidden, "forbidden");
+    assert_error(client.put("/admin/users/bob/privileges/ta").dispatch(), Status::Unauthorized, "unauthorized");
+}
+
+#[test]
+fn unknown_roles_and_last_admin_are_rejected() {
+    let client = client();
+    let admin = register_and_login(&client, "admin");
+    grant(&client, "admin", privileges::ADMIN);
+
+    assert_error(put_with_key(&client, "/admin/users/admin/privileges/wizard", &admin), Status::UnprocessableEntity, "validation_failed");
+    assert_error(delete_with_key(&client, "/admin/users/admin/privileges/admin", &admin), Status::Conflict, "conflict");
+}
+
+#[test]
+fn configured_admins_are_bootstrapped_at_launch() {
+    let path = std::env::temp_dir().join(format!("halp-test-{}.sqlite", keys::generate_key(16)));
+    let figment = || test_figment()
+        .merge(("database.in_memory", false))
+        .merge(("database.path", path.to_string_lossy().to_string()));
+
+    let client = Client::tracked(build_rocket(figment())).unwrap();
+    let key = register_and_login(&client, "root");
+    let token = last_link_token(&client, "/verify-email/");
+    drop(client);
+
+    // Unverified accounts aren't promoted
+    let admins = || figment().merge(("auth.admin_usernames", vec!["root", "nobody"]));
+    let client = Client::tracked(build_rocket(admins())).unwrap();
+    assert_error(get_with_key(&client, "/admin/users/root/privileges", &key), Status::Forbidden, "forbidden");
+    client.get(format!("/verify-email/{}", token)).dispatch();
+    drop(client);
+
+    let client = Client::tracked(build_rocket(admins())).unwrap();
+    assert_eq!(get_with_key(&client, "/admin/users/root/privileges", &key).status(), Status::Ok);
+    drop(client);
+
+    for suffix in ["", "-wal", "-shm"].iter() {
+        let _ = fs::remove_file(format!("{}{}", path.to_string_lossy(), suffix));
+    }
+}
+
+// Schema Migrations
+// A database in the shape the server created before migrations existed
+fn legacy_db(seed: &str) -> rusqlite::Connection {
+    let conn = rusqlite::Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        "CREATE TABLE users (unique_id INTEGER PRIMARY KEY, username TEXT UNIQUE NOT NULL, email TEXT UNIQUE, \
+            password_hash TEXT NOT NULL, password_salt TEXT NOT NULL, registration_datetime TEXT); \
+         CREATE TABLE authentication_keys (unique_id INTEGER PRIMARY KEY, user_id INTEGER NOT NULL, \
+            authentication_key TEXT NOT NULL, expiration TEXT NOT NULL); \
+         CREATE TABLE user_privileges (privilege_unique_id INTEGER PRIMARY KEY, user_id INTEGER NOT NULL, privilege TEXT NOT NULL); \
+         CREATE TABLE threads (unique_id INTEGER PRIMARY KEY, title TEXT NOT NULL, creator_uid INTEGER NOT NULL, \
+            creation_timestamp TEXT, tag TEXT, content TEXT NOT NULL); \
+         CREATE TABLE comments (unique_id INTEGER PRIMARY KEY, thread_id INTEGER NOT NULL, creator_uid INTEGER NOT NULL, \
+            creation_timestamp TEXT, content TEXT NOT NULL); \
+         INSERT INTO users (username, email, password_hash, password_salt) VALUES ('alice', 'alice@example.com', 'hash', 'salt');"
+    ).unwrap();
+    conn.execute_batch(seed).unwrap();
+    conn
+}
+
+fn pending_migrations(conn: &mut rusqlite::Connection) -> Vec<i64> {
+    migrations::status(conn).unwrap()
+        .into_iter()
+        .filter(|migration| migration.applied_at.is_none())
+        .map(|migration| migration.version)
+        .collect()
+}
+
+#[test]
+fn new_databases_are_fully_migrated_once() {
+    let client = client();
+    let mut conn = db_conn(&client);
+    assert!(pending_migrations(&mut conn).is_empty());
+    assert!(migrations::migrate(&mut conn, false).unwrap().is_empty());
+}
+
+#[test]
+fn legacy_database_is_brought_up_to_date() {
+    let mut conn = legacy_db("");
+    let applied = migrations::migrate(&mut conn, false).unwrap();
+    assert_eq!(applied.len(), migrations::MIGRATIONS.len());
+    assert!(pending_migrations(&mut conn).is_empty());
+
+    let column_count = |conn: &rusqlite::Connection, table: &str, column: &str| -> i64 {
+        conn.query_row(
+            &format!("SELECT COUNT(*) FROM pragma_table_info('{}') WHERE name = ?1", table),
+            params![column],
+            |row| row.get(0)
+        ).unwrap()
+    };
+    assert_eq!(column_count(&conn, "users", "password_salt"), 0);
+    assert_eq!(column_count(&conn, "users", "email_verified"), 1);
+    assert_eq!(column_count(&conn, "threads", "edited_at"), 1);
+    assert_eq!(column_count(&conn, "authentication_keys", "key_prefix"), 1);
+}
+
+#[test]
+fn legacy_moderators_become_tas() {
+    let mut conn = legacy_db(
+        "INSERT INTO user_privileges (user_id, privilege) VALUES (1, 'moderator'), (1, 'ta'), (1, 'moderator');"
+    );
+    migrations::migrate(&mut conn, false).unwrap();
+
+    assert_eq!(app_logic::get_user_roles(&mut conn, &String::from("1")).unwrap(), vec!["student", "ta"]);
+}
+
+#[test]
+fn plaintext_keys_are_rehashed() {
+    let legacy_key = "LegacyPlaintextKey0123456789abcd";
+    let mut conn = legacy_db(&format!(
+        "INSERT INTO authentication_keys (user_id, authentication_key, expiration) VALUES (1, '{}', '2999-01-01T00:00:00+00:00');",
+        legacy_key
+    ));
+    migrations::migrate(&mut conn, false).unwrap();
+
+    let stored: String = conn
+        .query_row("SELECT authentication_key FROM authentication_keys", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(stored, keys::hash_key(legacy_key));
+    let user = app_logic::current_user(&mut conn, legacy_key).unwrap().expect("legacy session");
+    assert_eq!(user.username, "alice");
+}
+
+#[test]
+fn dry_run_changes_nothing() {
+    let mut conn = legacy_db("");
+    let would_apply = migrations::migrate(&mut conn, true).unwrap();
+    assert_eq!(would_apply.len(), migrations::MIGRATIONS.len());
+    assert_eq!(pending_migrations(&mut conn).len(), migrations::MIGRATIONS.len());
+
+    let salt_columns: i64 = conn
+        .query_row("SELECT COUNT(*) FROM pragma_table_info('users') WHERE name = 'password_salt'", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(salt_columns, 1);
+}
+
+#[test]
+fn failed_migrations_are_rolled_back() {
+    // The key migration can't read a column that isn't there
+    let mut conn = legacy_db("ALTER TABLE authentication_keys RENAME COLUMN authentication_key TO legacy_key;");
+    assert!(migrations::migrate(&mut conn, false).is_err());
+    assert_eq!(pending_migrations(&mut conn).len(), migrations::MIGRATIONS.len());
+}
+
+#[test]
+fn databases_from_newer_builds_are_refused() {
+    let client = client();
+    let mut conn = db_conn(&client);
+    conn.execute(
+        "INSERT INTO schema_migrations (version, name, applied_at) VALUES (9999, 'from_the_future', '2999-01-01T00:00:00+00:00')",
+        []
+    ).unwrap();
+    assert!(migrations::migrate(&mut conn, false).is_err());
+}
+
+// Query Plans
+fn query_plan(conn: &rusqlite::Connection, sql: &str, query_params: &[SqlValue]) -> Vec<String> {
+    let mut plan_query = conn.prepare(&format!("EXPLAIN QUERY PLAN {}", sql)).unwrap();
+    let plan: Vec<String> = plan_query
+        .query_map(rusqlite::params_from_iter(query_params), |row| row.get(3))
+        .unwrap()
+        .map(|step| step.unwrap())
+        .collect();
+    plan
+}
+
+// Checks that a query searches (or walks in order) the given index instead of scanning a table, and
+// never sorts rows itself
+fn assert_uses_index(conn: &rusqlite::Connection, sql: &str, query_params: &[SqlValue], index: &str) {
+    let plan = query_plan(conn, sql, query_params);
+    assert!(plan.iter().any(|step| step.contains(index)), "{} doesn't use {}: {:?}", sql, index, plan);
+    assert!(!plan.iter().any(|step| step.starts_with("SCAN") && !step.contains(index)), "{} scans: {:?}", sql, plan);
+    assert!(!plan.iter().any(|step| step.contains("TEMP B-TREE")), "{} sorts: {:?}", sql, plan);
+}
+
+#[test]
+fn hot_queries_use_indexes() {
+    let client = client();
+    let conn = db_conn(&client);
+
+    // Tens of thousands of rows, so that a full scan would be noticeably slower than a search
+    conn.execute_batch(
+        "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000) \
+             INSERT INTO users (username, email, password_hash) SELECT 'user' || i, 'user' || i || '@example.com', 'hash' FROM n; \
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 20000) \
+             INSERT INTO authentication_keys (user_id, authentication_key, key_prefix, expiration) \
+             SELECT i % 1000 + 1, 'digest' || i, printf('%08d', i), '2999-01-01T00:00:00+00:00' FROM n; \
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 5000) \
+             INSERT INTO threads (title, creator_uid, creation_timestamp, tag, content) \
+             SELECT 'Thread ' || i, i % 1000 + 1, printf('2021-01-01T00:00:%05d+00:00', i), 'tag' || (i % 20), 'content' FROM n; \
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 30000) \
+             INSERT INTO comments (thread_id, creator_uid, creation_timestamp, content) \
+             SELECT i % 5000 + 1, i % 1000 + 1, printf('2021-01-01T00:00:%05d+00:00', i), 'comment' FROM n; \
+         INSERT INTO tags (name) SELECT DISTINCT tag FROM threads; \
+         INSERT INTO thread_tags (thread_id, tag_id) SELECT threads.unique_id, tags.unique_id FROM threads JOIN tags ON tags.name = threads.tag; \
+         ANALYZE;"
+    ).unwrap();
+
+    // Key lookup on every authenticated request, and the user's session list
+    assert_uses_index(&conn, "SELECT unique_id, user_id, authentication_key, expiration FROM authentication_keys WHERE key_prefix = '00000042'", &[], "authentication_keys_key_prefix");
+    assert_uses_index(&conn, "SELECT unique_id, key_prefix, expiration FROM authentication_keys WHERE user_id = 42 ORDER BY unique_id ASC", &[], "authentication_keys_user_id");
+    assert_uses_index(&conn, "DELETE FROM authentication_keys WHERE user_id = 42", &[], "authentication_keys_user_id");
+
+    // Every listing order, on the first page and past a cursor, exactly as the handlers query it
+    let no_filters = ThreadFilters { tag: None, author: None, created_before: None, created_after: None, has_comments: None };
+    let thread_orders = [
+        (SortOrder::Newest, "threads_creation_timestamp"),
+        (SortOrder::Oldest, "threads_creation_timestamp"),
+        (SortOrder::Active, "threads_last_activity_at"),
+        (SortOrder::Upvoted, "threads_upvotes"),
+    ];
+    for (sort, index) in thread_orders.iter() {
+        for cursor in [None, Some(cursor_for(*sort))].iter() {
+            let (sql, query_params) = app_logic::threads_query(&no_filters, *sort, 20, cursor.as_ref()).unwrap();
+            assert_uses_index(&conn, &sql, &query_params, index);
+        }
+    }
+    let comment_orders = [
+        (SortOrder::Oldest, "comments_thread_id_creation_timestamp"),
+        (SortOrder::Newest, "comments_thread_id_creation_timestamp"),
+        (SortOrder::Upvoted, "comments_thread_id_upvotes"),
+    ];
+    for (sort, index) in comment_orders.iter() {
+        for cursor in [None, Some(cursor_for(*sort))].iter() {
+            let (sql, query_params) = app_logic::comments_query(&String::from("42"), *sort, 20, cursor.as_ref()).unwrap();
+            assert_uses_index(&conn, &sql, &query_params, index);
+        }
+    }
+
+    // Threads with a tag are read in order too, checked against the thread_tags index that replaced
+    // the one on threads.tag
+    let dropped: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name = 'threads_tag_creation_timestamp'",
+        [],
+        |row| row.get(0)
+    ).unwrap();
+    assert_eq!(dropped, 0);
+    let tag_filter = ThreadFilters { tag: Some(String::from("tag7")), ..no_filters };
+    for cursor in [None, Some(cursor_for(SortOrder::Newest))].iter() {
+        let (sql, query_params) = app_logic::threads_query(&tag_filter, SortOrder::Newest, 20, cursor.as_ref()).unwrap();
+        assert_uses_index(&conn, &sql, &query_params, "threads_creation_timestamp");
+        let plan = query_plan(&conn, &sql, &query_params);
+        assert!(plan.iter().any(|step| step.contains("thread_tags_tag_id")), "{:?}", plan);
+    }
+}
+
+// A cursor part of the way through a listing
+fn cursor_for(sort: SortOrder) -> String {
+    let key = match sort {
+        SortOrder::Upvoted => SqlValue::Integer(3),
+        _ => SqlValue::Text(String::from("2021-01-01T00:00:00+00:00")),
+    };
+    Cursor { key, unique_id: 42 }.encode(sort)
+}
+
+// Authentication Key Handling
+#[test]
+fn missing_auth_key_is_unauthorized() {
+    let client = client();
+    assert_error(client.get("/threads").dispatch(), Status::Unauthorized, "unauthorized");
+}
+
+#[test]
+fn invalid_auth_key_is_unauthorized() {
+    let client = client();
+    assert_error(get_with_key(&client, "/threads", "not-a-real-key"), Status::Unauthorized, "unauthorized");
+}
+
+#[test]
+fn expired_auth_key_is_unauthorized() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+
+    // Expire every key in the database
+    db_conn(&client).execute(
+        "UPDATE authentication_keys SET expiration = ?1",
+        params!["2000-01-01T00:00:00+00:00"]
+    ).unwrap();
+
+    assert_error(get_with_key(&client, "/threads", &key), Status::Unauthorized, "unauthorized");
+}
+
+#[test]
+fn me_describes_the_signed_in_user() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    grant(&client, "alice", "ta");
+
+    let response = get_with_key(&client, "/me", &key);
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response);
+    assert_eq!(body["username"], "alice");
+    assert_eq!(body["display_name"], "alice");
+    assert_eq!(body["roles"], json!(["student", "ta"]));
+    assert_eq!(body["email_verified"], false);
+    assert!(body["unique_id"].is_string());
+
+    assert_error(client.get("/me").dispatch(), Status::Unauthorized, "unauthorized");
+}
+
+// Email Verification
+fn sent_mail(client: &Client) -> Vec<String> {
+    // Mail is delivered in the background
+    client.rocket().state::<MailTransport>().expect("managed mail transport").flush();
+    let config = client.rocket().state::<AppConfig>().expect("managed config");
+    let mut messages: Vec<(String, String)> = fs::read_dir(std::path::Path::new(&config.mail.maildir).join("new"))
+        .expect("maildir")
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            (path.to_string_lossy().to_string(), fs::read_to_string(&path).unwrap())
+        })
+        .collect();
+
+    // Maildir file names start with the delivery time
+    messages.sort();
+    messages.into_iter().map(|(_, message)| message).collect()
+}
+
+fn last_link_token(client: &Client, path: &str) -> String {
+    let messages = sent_mail(client);
+    let message = messages.last().expect("a sent email");
+    let start = message.find(path).expect("a link in the email") + path.len();
+    message[start..].split_whitespace().next().unwrap().to_string()
+}
+
+fn email_verified(client: &Client, username: &str) -> bool {
+    db_conn(client)
+        .query_row("SELECT email_verified FROM users WHERE username = ?1", params![username], |row| row.get(0))
+        .unwrap()
+}
+
+#[test]
+fn registration_emails_a_single_use_verification_link() {
+    let client = client();
+    register(&client, "alice");
+    assert!(!email_verified(&client, "alice"));
+
+    let message = sent_mail(&client).pop().unwrap();
+    assert!(message.contains("To: alice@example.com\r\n"));
+    let uri = format!("/verify-email/{}", last_link_token(&client, "/verify-email/"));
+
+    let response = client.get(uri.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(email_verified(&client, "alice"));
+
+    assert_error(client.get(uri).dispatch(), Status::NotFound, "not_found");
+}
+
+#[test]
+fn expired_verification_link_is_rejected() {
+    let client = client();
+    register(&client, "alice");
+    let token = last_link_token(&client, "/verify-email/");
+    db_conn(&client)
+        .execute("UPDATE user_tokens SET expiration = '2000-01-01T00:00:00+00:00'", [])
+        .unwrap();
+
+    assert_error(client.get(format!("/verify-email/{}", token)).dispatch(), Status::NotFound, "not_found");
+    assert!(!email_verified(&client, "alice"));
+}
+
+#[test]
+fn resending_verification_replaces_the_earlier_link() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    let first_token = last_link_token(&client, "/verify-email/");
+
+    assert_eq!(post_with_key(&client, "/verify-email/resend", &key).status(), Status::Ok);
+    let second_token = last_link_token(&client, "/verify-email/");
+    assert_ne!(first_token, second_token);
+
+    assert_error(client.get(format!("/verify-email/{}", first_token)).dispatch(), Status::NotFound, "not_found");
+    assert_eq!(client.get(format!("/verify-email/{}", second_token)).dispatch().status(), Status::Ok);
+    assert_error(post_with_key(&client, "/verify-email/resend", &key), Status::Conflict, "conflict");
+}
+
+#[test]
+fn unverified_users_can_be_kept_from_posting() {
+    let figment = test_figment().merge(("auth.require_verified_email", true));
+    let client = Client::tracked(build_rocket(figment)).unwrap();
+    let key = register_and_login(&client, "alice");
+
+    let response = post_json(&client, "/thread/create", Some(&key), json!({
+        "title": "Help",
+        "tag": "hw1",
+        "content": "How do I start?",
+    }));
+    assert_error(response, Status::Forbidden, "forbidden");
+
+    let token = last_link_token(&client, "/verify-email/");
+    client.get(format!("/verify-email/{}", token)).dispatch();
+    create_thread(&client, &key, "Help");
+}
+
+// A scripted relay that records everything the client sends (it can't do TLS, so STARTTLS is
+// turned down)
+fn scripted_relay(greeting: &'static [u8]) -> (u16, std::thread::JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let relay = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut transcript = String::new();
+        stream.write_all(greeting).unwrap();
+        let mut in_data = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                break;
+            }
+            transcript.push_str(&line);
+            let reply: &[u8] = if in_data {
+                if line != ".\r\n" {
+                    continue;
+                }
+                in_data = false;
+                b"250 queued\r\n"
+            } else if line.starts_with("EHLO") {
+                b"250-relay\r\n250 AUTH PLAIN\r\n"
+            } else if line.starts_with("STARTTLS") {
+                b"454 TLS not available\r\n"
+            } else if line.starts_with("AUTH") {
+                b"235 ok\r\n"
+            } else if line.starts_with("DATA") {
+                in_data = true;
+                b"354 go ahead\r\n"
+            } else if line.starts_with("QUIT") {
+                stream.write_all(b"221 bye\r\n").unwrap();
+                break;
+            } else {
+                b"250 ok\r\n"
+            };
+            stream.write_all(reply).unwrap();
+        }
+        transcript
+    });
+    (port, relay)
+}
+
+fn hello_email() -> Email {
+    Email {
+        to: String::from("alice@example.com"),
+        subject: String::from("Hello"),
+        body: String::from("First line\n.leading dot"),
+    }
+}
+
+#[test]
+fn smtp_mailer_speaks_smtp() {
+    let (port, relay) = scripted_relay(b"220 relay ready\r\n");
+    let mailer = SmtpMailer::new(MailConfig {
+        transport: String::from("smtp"),
+        smtp_host: String::from("127.0.0.1"),
+        smtp_port: port,
+        smtp_tls: String::from("none"),
+        ..MailConfig::default()
+    });
+    mailer.send(&hello_email()).unwrap();
+
+    let transcript = relay.join().unwrap();
+    assert!(!transcript.contains("AUTH"));
+    assert!(transcript.contains("RCPT TO:<alice@example.com>\r\n"));
+    assert!(transcript.contains("Subject: Hello\r\n"));
+    assert!(transcript.contains("\r\n..leading dot\r\n.\r\n"));
+}
+
+#[test]
+fn smtp_credentials_are_never_sent_in_the_clear() {
+    // A relay that can't upgrade the connection doesn't get to see the password
+    let (port, relay) = scripted_relay(b"220 relay ready\r\n");
+    let mut config = MailConfig {
+        transport: String::from("smtp"),
+        smtp_host: String::from("127.0.0.1"),
+        smtp_port: port,
+        smtp_username: Some(String::from("halp")),
+        smtp_password: Some(String::from("secret")),
+        ..MailConfig::default()
+    };
+    assert!(SmtpMailer::new(config.clone()).send(&hello_email()).is_err());
+    let transcript = relay.join().unwrap();
+    assert!(transcript.contains("STARTTLS\r\n"));
+    assert!(!transcript.contains("AUTH"));
+
+    // Turning TLS off isn't allowed while credentials are set
+    config.smtp_tls = String::from("none");
+    assert!(SmtpMailer::new(config.clone()).send(&hello_email()).is_err());
+    let app_config = AppConfig { mail: config, ..AppConfig::default() };
+    assert_eq!(app_config.validate().len(), 1);
+}
+
+#[test]
+fn unresponsive_mail_servers_dont_hold_up_requests() {
+    // A relay that accepts connections but never says anything
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let figment = test_figment()
+        .merge(("mail.transport", "smtp"))
+        .merge(("mail.smtp_host", "127.0.0.1"))
+        .merge(("mail.smtp_port", listener.local_addr().unwrap().port()))
+        .merge(("mail.smtp_tls", "none"))
+        .merge(("mail.smtp_timeout_secs", 5));
+    let client = Client::tracked(build_rocket(figment)).unwrap();
+
+    let started = std::time::Instant::now();
+    register(&client, "alice");
+    assert!(started.elapsed() < std::time::Duration::from_secs(2));
+}
+
+#[test]
+fn password_resets_answer_alike_when_mail_is_unavailable() {
+    // Nothing listens on the relay's port, so every delivery fails
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let figment = test_figment()
+        .merge(("mail.transport", "smtp"))
+        .merge(("mail.smtp_host", "127.0.0.1"))
+        .merge(("mail.smtp_port", port))
+        .merge(("mail.smtp_tls", "none"));
+    let client = Client::tracked(build_rocket(figment)).unwrap();
+    register(&client, "alice");
+
+    let known = post_json(&client, "/password/forgot", None, json!({"email": "alice@example.com"}));
+    assert_eq!(known.status(), Status::Ok);
+    let known_body = known.into_string();
+    let unknown = post_json(&client, "/password/forgot", None, json!({"email": "nobody@example.com"}));
+    assert_eq!(unknown.status(), Status::Ok);
+    assert_eq!(known_body, unknown.into_string());
+}
+
+#[test]
+fn malformed_smtp_replies_are_errors() {
+    // A multibyte character right after the code, and a reply too short to have one
+    let greetings: [&'static [u8]; 3] = [b"554\xc3\xa9 go away\r\n", b"2\r\n", b"\xe2\x82\xac\r\n"];
+    for greeting in greetings.iter() {
+        let (port, relay) = scripted_relay(greeting);
+        let mailer = SmtpMailer::new(MailConfig {
+            transport: String::from("smtp"),
+            smtp_host: String::from("127.0.0.1"),
+            smtp_port: port,
+            smtp_tls: String::from("none"),
+            ..MailConfig::default()
+        });
+        assert!(mailer.send(&hello_email()).is_err());
+        drop(mailer);
+        relay.join().unwrap();
+    }
+}
+
+// Logout & Sessions
+fn login_with_agent(client: &Client, username: &str, user_agent: &str) -> String {
+    let response = client.post("/login")
+        .header(ContentType::JSON)
+        .header(Header::new("User-Agent", user_agent.to_string()))
+        .body(json!({"username": username, "password": "correct horse battery staple"}).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    json_body(response)["auth_key"].as_str().unwrap().to_string()
+}
+
+fn post_with_key<'c>(client: &'c Client, uri: &'c str, key: &str) -> LocalResponse<'c> {
+    client.post(uri).header(Header::new("x-auth-key", key.to_string())).dispatch()
+}
+
+#[test]
+fn logout_revokes_presented_key() {
+    let client = client();
+    register(&client, "alice");
+    let laptop = login(&client, "alice");
+    let phone = login(&client, "alice");
+
+    assert_eq!(post_with_key(&client, "/logout", &laptop).status(), Status::Ok);
+    assert_error(get_with_key(&client, "/threads", &laptop), Status::Unauthorized, "unauthorized");
+    assert_eq!(get_with_key(&client, "/threads", &phone).status(), Status::Ok);
+}
+
+#[test]
+fn logout_all_revokes_every_key() {
+    let client = client();
+    register(&client, "alice");
+    let bob = register_and_login(&client, "bob");
+    let laptop = login(&client, "alice");
+    let phone = login(&client, "alice");
+
+    let response = post_with_key(&client, "/logout/all", &laptop);
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(json_body(response)["revoked"], 2);
+    assert_error(get_with_key(&client, "/threads", &phone), Status::Unauthorized, "unauthorized");
+    assert_eq!(get_with_key(&client, "/threads", &bob).status(), Status::Ok);
+}
+
+#[test]
+fn sessions_are_listed_and_revocable() {
+    let client = client();
+    register(&client, "alice");
+    let laptop = login_with_agent(&client, "alice", "Laptop Browser");
+    let phone = login_with_agent(&client, "alice", "Phone Browser");
+
+    let body = json_body(get_with_key(&client, "/sessions", &laptop));
+    let sessions = body["sessions"].as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions[0]["user_agent"], "Laptop Browser");
+    assert_eq!(sessions[0]["current"], true);
+    assert!(sessions[0]["last_used"].is_string());
+    assert!(sessions[0]["created_at"].is_string());
+    assert_eq!(sessions[1]["user_agent"], "Phone Browser");
+    assert_eq!(sessions[1]["current"], false);
+
+    // Revoke the phone's session from the laptop
+    let uri = format!("/sessions/{}", sessions[1]["session_id"]);
+    assert_eq!(client.delete(uri).header(Header::new("x-auth-key", laptop.clone())).dispatch().status(), Status::Ok);
+    assert_error(get_with_key(&client, "/threads", &phone), Status::Unauthorized, "unauthorized");
+}
+
+#[test]
+fn cannot_revoke_another_users_session() {
+    let client = client();
+    let alice = register_and_login(&client, "alice");
+    let bob = register_and_login(&client, "bob");
+
+    let body = json_body(get_with_key(&client, "/sessions", &alice));
+    let uri = format!("/sessions/{}", body["sessions"][0]["session_id"]);
+    let response = client.delete(uri).header(Header::new("x-auth-key", bob)).dispatch();
+    assert_error(response, Status::NotFound, "not_found");
+    assert_eq!(get_with_key(&client, "/threads", &alice).status(), Status::Ok);
+}
+
+// Password Change & Reset
+fn login_with_password(client: &Client, username: &str, password: &str) -> Status {
+    post_json(client, "/login", None, json!({"username": username, "password": password})).status()
+}
+
+#[test]
+fn password_change_requires_current_password() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    let response = post_json(&client, "/password/change", Some(&key), json!({
+        "current_password": "not the password",
+        "new_password": "a brand new passphrase",
+    }));
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(field_errors(response), vec!["current_password"]);
+
+    let response = post_json(&client, "/password/change", Some(&key), json!({
+        "current_password": "correct horse battery staple",
+        "new_password": "password123",
+    }));
+    assert_eq!(field_errors(response), vec!["new_password"]);
+}
+
+#[test]
+fn password_change_signs_out_other_sessions() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    let other_key = login(&client, "alice");
+
+    let response = post_json(&client, "/password/change", Some(&key), json!({
+        "current_password": "correct horse battery staple",
+        "new_password": "a brand new passphrase",
+    }));
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(json_body(response)["revoked"], 1);
+
+    assert_eq!(get_with_key(&client, "/sessions", &key).status(), Status::Ok);
+    assert_eq!(get_with_key(&client, "/sessions", &other_key).status(), Status::Unauthorized);
+    assert_eq!(login_with_password(&client, "alice", "correct horse battery staple"), Status::Unauthorized);
+    assert_eq!(login_with_password(&client, "alice", "a brand new passphrase"), Status::Ok);
+}
+
+#[test]
+fn forgot_password_does_not_reveal_unknown_emails() {
+    let client = client();
+    register(&client, "alice");
+    let sent = sent_mail(&client).len();
+
+    let response = post_json(&client, "/password/forgot", None, json!({"email": "nobody@example.com"}));
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(json_body(response)["success"], true);
+    assert_eq!(sent_mail(&client).len(), sent);
+}
+
+#[test]
+fn password_reset_consumes_the_emailed_token() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    let response = post_json(&client, "/password/forgot", None, json!({"email": "Alice@Example.com"}));
+    assert_eq!(response.status(), Status::Ok);
+    let token = last_link_token(&client, "reset token:");
+
+    // A password that fails the policy leaves the token usable
+    let response = post_json(&client, "/password/reset", None, json!({"token": token, "new_password": "short"}));
+    assert_eq!(field_errors(response), vec!["new_password"]);
+
+    let response = post_json(&client, "/password/reset", None, json!({"token": token, "new_password": "a brand new passphrase"}));
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(get_with_key(&client, "/sessions", &key).status(), Status::Unauthorized);
+    assert_eq!(login_with_password(&client, "alice", "a brand new passphrase"), Status::Ok);
+
+    let response = post_json(&client, "/password/reset", None, json!({"token": token, "new_password": "another new passphrase"}));
+    assert_error(response, Status::NotFound, "not_found");
+}
+
+// Key Storage
+#[test]
+fn keys_are_stored_hashed() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+
+    let (stored, prefix): (String, String) = db_conn(&client)
+        .query_row("SELECT authentication_key, key_prefix FROM authentication_keys", [], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap();
+    assert_ne!(stored, key);
+    assert_eq!(stored, keys::hash_key(&key));
+    assert_eq!(prefix, key[..keys::KEY_PREFIX_LENGTH]);
+}
+
+#[test]
+fn constant_time_eq_compares_whole_strings() {
+    assert!(keys::constant_time_eq("abcdef", "abcdef"));
+    assert!(!keys::constant_time_eq("abcdef", "abcdeg"));
+    assert!(!keys::constant_time_eq("abc", "abcdef"));
+}
+
+// Password Hashing
+fn stored_hash(client: &Client, username: &str) -> String {
+    db_conn(client)
+        .query_row("SELECT password_hash FROM users WHERE username = ?1", params![username], |row| row.get(0))
+        .unwrap()
+}
+
+#[test]
+fn passwords_are_hashed_with_configured_parameters() {
+    let client = client();
+    register(&client, "alice");
+    assert!(stored_hash(&client, "alice").starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+}
+
+#[test]
+fn weaker_hashes_are_upgraded_on_login() {
+    let client = client();
+    register(&client, "alice");
+
+    // A hash from before the parameters were configurable
+    let legacy_hash = argon2::hash_encoded(b"correct horse battery staple", b"legacysalt", &argon2::Config::default()).unwrap();
+    db_conn(&client)
+        .execute("UPDATE users SET password_hash = ?1 WHERE username = 'alice'", params![legacy_hash])
+        .unwrap();
+
+    login(&client, "alice");
+    let upgraded_hash = stored_hash(&client, "alice");
+    assert!(upgraded_hash.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+
+    // Hashes that already meet the configured parameters are left alone
+    login(&client, "alice");
+    assert_eq!(stored_hash(&client, "alice"), upgraded_hash);
+}
+
+// Threads & Comments
+#[test]
+fn create_and_list_threads() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    assert!(threads(&client, &key).is_empty());
+
+    create_thread(&client, &key, "First question");
+    let listed = threads(&client, &key);
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0]["title"], "First question");
+    assert_eq!(listed[0]["tags"], json!(["hw1"]));
+    assert!(listed[0]["edited_at"].is_null());
+}
+
+#[test]
+fn listings_describe_authors_and_activity() {
+    let client = client();
+    let alice = register_and_login(&client, "alice");
+    let bob = register_and_login(&client, "bob");
+    grant(&client, "bob", "ta");
+    grant(&client, "bob", "instructor");
+    create_thread(&client, &alice, "First question");
+    assert_eq!(threads(&client, &alice)[0]["comment_count"], 0);
+
+    let response = patch_json(&client, "/me", &alice, json!({"display_name": "  Alice Liddell "}));
+    assert_eq!(response.status(), Status::Ok);
+    create_comment(&client, &bob, 1);
+    create_comment(&client, &alice, 1);
+
+    let thread = threads(&client, &alice)[0].clone();
+    assert_eq!(thread["author"], json!({"unique_id": 1, "username": "alice", "display_name": "Alice Liddell", "badge": null}));
+    assert_eq!(thread["comment_count"], 2);
+    let comments = comments(&client, &alice, 1);
+    assert_eq!(thread["last_activity_at"], comments[1]["creation_timestamp"]);
+    assert_eq!(comments[0]["author"], json!({"unique_id": 2, "username": "bob", "display_name": "bob", "badge": "instructor"}));
+}
+
+#[test]
+fn display_names_are_validated() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+
+    assert_eq!(field_errors(patch_json(&client, "/me", &key, json!({"display_name": "a".repeat(51)}))), vec!["display_name"]);
+    assert_eq!(patch_json(&client, "/me", &key, json!({"display_name": "Al"})).status(), Status::Ok);
+    assert_eq!(json_body(get_with_key(&client, "/me", &key))["display_name"], "Al");
+    assert_eq!(patch_json(&client, "/me", &key, json!({"display_name": " "})).status(), Status::Ok);
+    assert_eq!(json_body(get_with_key(&client, "/me", &key))["display_name"], "alice");
+}
+
+#[test]
+fn create_thread_requires_auth() {
+    let client = client();
+    let response = post_json(&client, "/thread/create", None, json!({
+        "title": "t",
+        "tag": "hw1",
+        "content": "c",
+    }));
+    assert_error(response, Status::Unauthorized, "unauthorized");
+}
+
+#[test]
+fn create_and_list_comments() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    create_thread(&client, &key, "First question");
+    create_comment(&client, &key, 1);
+
+    let listed = comments(&client, &key, 1);
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0]["content"], "Read the handout");
+    assert_eq!(listed[0]["thread_id"], 1);
+}
+
+#[test]
+fn comments_for_missing_thread_not_found() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    assert_error(get_with_key(&client, "/threads/42/comments", &key), Status::NotFound, "not_found");
+}
+
+#[test]
+fn comment_on_missing_thread_not_found() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    let response = post_json(&client, "/thread/42/create_comment", Some(&key), json!({"content": "hello"}));
+    assert_error(response, Status::NotFound, "not_found");
+}
+
+// Pagination & Sorting
+fn page(client: &Client, key: &str, uri: &str) -> (Vec<i64>, Option<String>) {
+    let response = get_with_key(client, uri, key);
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response);
+    let list = if body["threads"].is_array() { &body["threads"] } else { &body["comments"] };
+    let ids = list.as_array().unwrap().iter().map(|item| item["unique_id"].as_i64().unwrap()).collect();
+    (ids, body["next_cursor"].as_str().map(String::from))
+}
+
+fn all_pages(client: &Client, key: &str, uri: &str) -> Vec<Vec<i64>> {
+    let separator = if uri.contains('?') { "&" } else { "?" };
+    let (ids, mut cursor) = page(client, key, uri);
+    let mut pages = vec![ids];
+    while let Some(next) = cursor {
+        let (ids, next_cursor) = page(client, key, &format!("{}{}cursor={}", uri, separator, next));
+        pages.push(ids);
+        cursor = next_cursor;
+    }
+    pages
+}
+
+#[test]
+fn threads_are_paginated_newest_first() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    for i in 1..=5 {
+        create_thread(&client, &key, &format!("Question {}", i));
+    }
+
+    assert_eq!(all_pages(&client, &key, "/threads?limit=2"), vec![vec![5, 4], vec![3, 2], vec![1]]);
+    assert_eq!(all_pages(&client, &key, "/threads?sort=oldest&limit=3"), vec![vec![1, 2, 3], vec![4, 5]]);
+    assert_eq!(all_pages(&client, &key, "/threads"), vec![vec![5, 4, 3, 2, 1]]);
+}
+
+#[test]
+fn pages_stay_stable_while_threads_are_added() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    for i in 1..=4 {
+        create_thread(&client, &key, &format!("Question {}", i));
+    }
+
+    let (first, cursor) = page(&client, &key, "/threads?limit=2");
+    assert_eq!(first, vec![4, 3]);
+    create_thread(&client, &key, "Late question");
+    let (second, _) = page(&client, &key, &format!("/threads?limit=2&cursor={}", cursor.unwrap()));
+    assert_eq!(second, vec![2, 1]);
+}
+
+#[test]
+fn threads_sort_by_activity_and_upvotes() {
+    let client = client();
+    let alice = register_and_login(&client, "alice");
+    let bob = register_and_login(&client, "bob");
+    for i in 1..=3 {
+        create_thread(&client, &alice, &format!("Question {}", i));
+    }
+
+    // Commenting bumps a thread's activity
+    create_comment(&client, &bob, 1);
+    assert_eq!(all_pages(&client, &alice, "/threads?sort=active"), vec![vec![1, 3, 2]]);
+
+    // Upvotes count once per user, and can be taken back
+    for key in [&alice, &bob, &bob].iter() {
+        assert_eq!(put_with_key(&client, "/thread/2/vote", key).status(), Status::Ok);
+    }
+    assert_eq!(put_with_key(&client, "/thread/3/vote", &bob).status(), Status::Ok);
+    assert_eq!(all_pages(&client, &alice, "/threads?sort=upvoted&limit=1"), vec![vec![2], vec![3], vec![1]]);
+    assert_eq!(threads(&client, &alice).iter().find(|t| t["unique_id"] == 2).unwrap()["upvotes"], 2);
+
+    assert_eq!(delete_with_key(&client, "/thread/2/vote", &alice).status(), Status::Ok);
+    assert_eq!(delete_with_key(&client, "/thread/2/vote", &bob).status(), Status::Ok);
+    assert_eq!(all_pages(&client, &alice, "/threads?sort=upvoted"), vec![vec![3, 2, 1]]);
+    assert_error(put_with_key(&client, "/thread/42/vote", &bob), Status::NotFound, "not_found");
+}
+
+#[test]
+fn comments_are_paginated_oldest_first() {
+    let client = client();
+    let alice = register_and_login(&client, "alice");
+    let bob = register_and_login(&client, "bob");
+    create_thread(&client, &alice, "First question");
+    for _ in 0..3 {
+        create_comment(&client, &bob, 1);
+    }
+
+    assert_eq!(all_pages(&client, &alice, "/threads/1/comments?limit=2"), vec![vec![1, 2], vec![3]]);
+    assert_eq!(all_pages(&client, &alice, "/threads/1/comments?sort=newest"), vec![vec![3, 2, 1]]);
+
+    assert_eq!(put_with_key(&client, "/thread/1/comment/2/vote", &alice).status(), Status::Ok);
+    assert_eq!(all_pages(&client, &alice, "/threads/1/comments?sort=upvoted&limit=1"), vec![vec![2], vec![3], vec![1]]);
+    assert_error(put_with_key(&client, "/thread/1/comment/42/vote", &alice), Status::NotFound, "not_found");
+}
+
+#[test]
+fn threads_are_filtered() {
+    let client = client();
+    let alice = register_and_login(&client, "alice");
+    let bob = register_and_login(&client, "bob");
+    let threads = [("alice", "homework-3", "2021-02-27T12:00:00+00:00"), ("bob", "homework-3", "2021-03-01T09:30:00+00:00"),
+        ("alice", "exam", "2021-03-02T08:00:00+00:00"), ("bob", "homework-3", "2021-03-05T18:45:00+00:00")];
+    for (author, tag, created) in threads.iter() {
+        let key = if *author == "alice" { &alice } else { &bob };
+        let response = post_json(&client, "/thread/create", Some(key), json!({"title": "Question", "tag": tag, "content": "Help"}));
+        assert_eq!(response.status(), Status::Ok);
+        db_conn(&client).execute(
+            "UPDATE threads SET creation_timestamp = ?1, last_activity_at = ?1 WHERE unique_id = (SELECT MAX(unique_id) FROM threads)",
+            params![created]
+        ).unwrap();
+    }
+    create_comment(&client, &alice, 2);
+
+    assert_eq!(all_pages(&client, &alice, "/threads?tag=homework-3"), vec![vec![4, 2, 1]]);
+    assert_eq!(all_pages(&client, &alice, "/threads?author=ALICE"), vec![vec![3, 1]]);
+    assert_eq!(all_pages(&client, &alice, "/threads?author=nobody"), vec![Vec::<i64>::new()]);
+    assert_eq!(all_pages(&client, &alice, "/threads?created_after=2021-03-01&created_before=2021-03-05"), vec![vec![3, 2]]);
+    assert_eq!(all_pages(&client, &alice, "/threads?created_after=2021-03-01T10:30:00%2B01:00"), vec![vec![4, 3, 2]]);
+    assert_eq!(all_pages(&client, &alice, "/threads?has_comments=true"), vec![vec![2]]);
+    assert_eq!(all_pages(&client, &alice, "/threads?unanswered=true&tag=homework-3&author=bob"), vec![vec![4]]);
+
+    // Filters carry over to later pages along with the cursor
+    assert_eq!(all_pages(&client, &alice, "/threads?tag=homework-3&sort=oldest&limit=2"), vec![vec![1, 2], vec![4]]);
+
+    // Filter values are bound as parameters, never spliced into the query
+    assert_eq!(all_pages(&client, &alice, "/threads?tag=%27%20OR%201%3D1%20--"), vec![Vec::<i64>::new()]);
+}
+
+#[test]
+fn invalid_thread_filters_are_rejected() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+
+    let response = get_with_key(&client, "/threads?created_before=yesterday&created_after=2021-13-01", &key);
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(field_errors(response), vec!["created_before", "created_after"]);
+
+    let response = get_with_key(&client, "/threads?has_comments=true&unanswered=true", &key);
+    assert_eq!(field_errors(response), vec!["unanswered"]);
+}
+
+#[test]
+fn invalid_listing_options_are_rejected() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    for i in 1..=3 {
+        create_thread(&client, &key, &format!("Question {}", i));
+    }
+    let (_, cursor) = page(&client, &key, "/threads?limit=1");
+    let cursor = cursor.unwrap();
+
+    assert_error(get_with_key(&client, "/threads?sort=hottest", &key), Status::UnprocessableEntity, "validation_failed");
+    assert_error(get_with_key(&client, "/threads/1/comments?sort=active", &key), Status::UnprocessableEntity, "validation_failed");
+    assert_error(get_with_key(&client, "/threads?limit=0", &key), Status::UnprocessableEntity, "validation_failed");
+    assert_error(get_with_key(&client, "/threads?limit=1000", &key), Status::UnprocessableEntity, "validation_failed");
+    assert_error(get_with_key(&client, "/threads?cursor=garbage", &key), Status::UnprocessableEntity, "validation_failed");
+    let uri = format!("/threads?sort=upvoted&cursor={}", cursor);
+    assert_error(get_with_key(&client, &uri, &key), Status::UnprocessableEntity, "validation_failed");
+}
+
+// Search
+fn post_thread(client: &Client, key: &str, title: &str, tag: &str, content: &str) {
+    let response = post_json(client, "/thread/create", Some(key), json!({"title": title, "tag": tag, "content": content}));
+    assert_eq!(response.status(), Status::Ok);
+}
+
+fn post_comment(client: &Client, key: &str, thread_id: i64, content: &str) {
+    let uri = format!("/thread/{}/create_comment", thread_id);
+    let response = post_json(client, &uri, Some(key), json!({"content": content}));
+    assert_eq!(response.status(), Status::Ok);
+}
+
+fn search(client: &Client, key: &str, query: &str) -> Vec<Value> {
+    let uri = format!("/search?{}", query);
+    let response = get_with_key(client, &uri, key);
+    assert_eq!(response.status(), Status::Ok);
+    json_body(response)["results"].as_array().expect("results array").clone()
+}
+
+fn result_ids(results: &[Value]) -> Vec<(i64, Option<i64>)> {
+    results.iter().map(|result| (result["thread_id"].as_i64().unwrap(), result["comment_id"].as_i64())).collect()
+}
+
+#[test]
+fn search_ranks_threads_and_comments() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    post_thread(&client, &key, "Stack overflow in my function", "homework-3", "It crashes when the list is long, probably recursion");
+    post_thread(&client, &key, "Recursion base case", "homework-3", "What should the base case of the recursion be?");
+    post_comment(&client, &key, 1, "Your recursion never reaches the base case");
+
+    let results = search(&client, &key, "q=recursion");
+    assert_eq!(result_ids(&results)[0], (2, None));
+    assert_eq!(results.len(), 3);
+    assert!(result_ids(&results).contains(&(1, Some(1))));
+
+    let thread = &results[0];
+    assert_eq!(thread["kind"], "thread");
+    assert_eq!(thread["title"], "<mark>Recursion</mark> base case");
+    assert_eq!(thread["snippet"], "What should the base case of the <mark>recursion</mark> be?");
+    let comment = results.iter().find(|result| result["kind"] == "comment").unwrap();
+    assert_eq!(comment["title"], "Stack overflow in my function");
+}
+
+#[test]
+fn search_highlights_cannot_be_forged() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    post_thread(&client, &key, "Official answer", "homework-3", "The answer is in the recursion notes");
+    post_comment(&client, &key, 1, "Recursion is fine");
+
+    // Posts that contain the markers themselves
+    let conn = db_conn(&client);
+    conn.execute("UPDATE threads SET title = char(1) || 'Official' || char(2) || ' answer', \
+        content = 'The ' || char(1) || 'answer' || char(2) || ' is in the recursion notes'", []).unwrap();
+    conn.execute("UPDATE comments SET content = 'Recursion ' || char(1) || 'is' || char(2) || ' fine'", []).unwrap();
+    drop(conn);
+
+    let results = search(&client, &key, "q=recursion");
+    let thread = results.iter().find(|result| result["kind"] == "thread").unwrap();
+    assert_eq!(thread["title"], "Official answer");
+    assert_eq!(thread["snippet"], "The answer is in the <mark>recursion</mark> notes");
+    let comment = results.iter().find(|result| result["kind"] == "comment").unwrap();
+    assert_eq!(comment["title"], "\u{1}Official\u{2} answer");
+    assert_eq!(comment["snippet"], "<mark>Recursion</mark> is fine");
+}
+
+#[test]
+fn search_supports_phrases_prefixes_and_tags() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    post_thread(&client, &key, "Linked lists", "homework-3", "How do I reverse a linked list in place?");
+    post_thread(&client, &key, "Lists of links", "exam", "Is there a list of the links from lecture?");
+    post_comment(&client, &key, 2, "The linked list question from homework is similar");
+
+    assert_eq!(result_ids(&search(&client, &key, "q=%22linked%20list%22")), vec![(1, None), (2, Some(1))]);
+    assert_eq!(result_ids(&search(&client, &key, "q=%22list%20linked%22")), Vec::<(i64, Option<i64>)>::new());
+    assert_eq!(search(&client, &key, "q=lectu*").len(), 1);
+    assert_eq!(result_ids(&search(&client, &key, "q=homework&tag=exam")), vec![(2, Some(1))]);
+    assert_eq!(result_ids(&search(&client, &key, "q=homework")).len(), 2);
+}
+
+#[test]
+fn search_index_follows_edits_and_deletes() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    post_thread(&client, &key, "Pointers", "homework-3", "Segfault when dereferencing");
+    post_comment(&client, &key, 1, "Check for null before dereferencing");
+
+    let response = patch_json(&client, "/thread/1", &key, json!({"content": "Solved it, the pointer was dangling"}));
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(result_ids(&search(&client, &key, "q=dereferencing")), vec![(1, Some(1))]);
+    assert_eq!(result_ids(&search(&client, &key, "q=dangling")), vec![(1, None)]);
+
+    assert_eq!(delete_with_key(&client, "/thread/1", &key).status(), Status::Ok);
+    assert!(search(&client, &key, "q=dereferencing").is_empty());
+    assert!(search(&client, &key, "q=dangling").is_empty());
+    let indexed: i64 = db_conn(&client).query_row("SELECT COUNT(*) FROM search_index", [], |row| row.get(0)).unwrap();
+    assert_eq!(indexed, 0);
+}
+
+#[test]
+fn search_input_is_never_query_syntax() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    post_thread(&client, &key, "Escaping <b>tags</b>", "misc", "Use &lt; for <");
+
+    // FTS5 operators and column filters are searched for as plain words (and never fail to parse)
+    for query in ["q=tags%20OR", "q=title%3Atags", "q=tags%20NEAR(", "q=tags%20AND%20NOT"].iter() {
+        assert_eq!(search(&client, &key, query).len(), 0, "{}", query);
+    }
+    for query in ["q=%22escaping%20b", "q=-tags%20%5E", "q=misc%3A"].iter() {
+        assert_eq!(search(&client, &key, query).len(), 1, "{}", query);
+    }
+
+    // Matched text is HTML-escaped around the highlights
+    let results = search(&client, &key, "q=tags");
+    assert_eq!(results[0]["title"], "Escaping &lt;b&gt;<mark>tags</mark>&lt;/b&gt;");
+
+    assert_error(get_with_key(&client, "/search?q=%2A%20-%20%22%22", &key), Status::UnprocessableEntity, "validation_failed");
+    assert_error(get_with_key(&client, "/search", &key), Status::UnprocessableEntity, "validation_failed");
+
+    assert_eq!(search::fts_query("linked \"base case\" rec* \"un closed"), Some(String::from("\"linked\" \"base case\" \"rec\"* \"un closed\"")));
+}
+
+// Tags
+fn tag_counts(client: &Client, key: &str) -> Vec<(String, i64)> {
+    let response = get_with_key(client, "/tags", key);
+    assert_eq!(response.status(), Status::Ok);
+    json_body(response)["tags"]
+        .as_array()
+        .expect("tags array")
+        .iter()
+        .map(|tag| (tag["name"].as_str().unwrap().to_string(), tag["thread_count"].as_i64().unwrap()))
+        .collect()
+}
+
+#[test]
+fn tags_are_merged_by_canonical_name() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    post_thread(&client, &key, "Loops", "HW1", "Help");
+    let response = post_json(&client, "/thread/create", Some(&key), json!({
+        "title": "Recursion", "tags": ["hw1", "Homework 3", "HW1"], "content": "Help"
+    }));
+    assert_eq!(response.status(), Status::Ok);
+
+    assert_eq!(threads(&client, &key)[0]["tags"], json!(["hw1", "homework-3"]));
+    assert_eq!(tag_counts(&client, &key), vec![(String::from("hw1"), 2), (String::from("homework-3"), 1)]);
+    assert_eq!(all_pages(&client, &key, "/threads?tag=HW1"), vec![vec![2, 1]]);
+    assert_eq!(result_ids(&search(&client, &key, "q=recursion&tag=Homework%203")), vec![(2, None)]);
+}
+
+#[test]
+fn threads_can_be_retagged() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    create_thread(&client, &key, "First question");
+
+    let response = patch_json(&client, "/thread/1", &key, json!({"tags": ["exam", "week-2"]}));
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(threads(&client, &key)[0]["tags"], json!(["exam", "week-2"]));
+    assert_eq!(tag_counts(&client, &key), vec![(String::from("exam"), 1), (String::from("week-2"), 1)]);
+
+    let too_many = json!({"tags": ["a1", "a2", "a3", "a4", "a5", "a6"]});
+    assert_eq!(field_errors(patch_json(&client, "/thread/1", &key, too_many)), vec!["tags"]);
+    assert_eq!(field_errors(patch_json(&client, "/thread/1", &key, json!({"tags": []}))), vec!["tags"]);
+    assert_eq!(field_errors(patch_json(&client, "/thread/1", &key, json!({"tags": ["what?"]}))), vec!["tags"]);
+}
+
+#[test]
+fn staff_manage_tags() {
+    let client = client();
+    let alice = register_and_login(&client, "alice");
+    let bob = register_and_login(&client, "bob");
+    grant(&client, "bob", "instructor");
+
+    let new_tag = json!({"name": "Midterm Exam", "description": "Questions about the midterm", "color": "#1F77B4"});
+    assert_error(post_json(&client, "/tags", Some(&alice), new_tag.clone()), Status::Forbidden, "forbidden");
+    let response = post_json(&client, "/tags", Some(&bob), new_tag.clone());
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(json_body(response)["name"], "midterm-exam");
+    assert_error(post_json(&client, "/tags", Some(&bob), new_tag), Status::Conflict, "conflict");
+    assert_eq!(field_errors(post_json(&client, "/tags", Some(&bob), json!({"name": "quiz", "color": "blue"}))), vec!["color"]);
+
+    let response = patch_json(&client, "/tags/midterm-exam", &bob, json!({"description": "Midterm logistics", "color": ""}));
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(get_with_key(&client, "/tags", &alice));
+    assert_eq!(body["tags"], json!([{
+        "name": "midterm-exam", "description": "Midterm logistics", "color": null, "predefined": true, "thread_count": 0
+    }]));
+
+    post_thread(&client, &alice, "Room?", "midterm-exam", "Where is it?");
+    assert_error(delete_with_key(&client, "/tags/midterm-exam", &bob), Status::Conflict, "conflict");
+    assert_eq!(delete_with_key(&client, "/thread/1", &alice).status(), Status::Ok);
+    assert_eq!(delete_with_key(&client, "/tags/midterm-exam", &bob).status(), Status::Ok);
+    assert_error(delete_with_key(&client, "/tags/midterm-exam", &bob), Status::NotFound, "not_found");
+}
+
+#[test]
+fn tags_can_be_restricted_to_predefined_ones() {
+    let client = Client::tracked(build_rocket(test_figment().merge(("content.restrict_tags", true)))).unwrap();
+    let alice = register_and_login(&client, "alice");
+    let bob = register_and_login(&client, "bob");
+    grant(&client, "bob", "instructor");
+    assert_eq!(post_json(&client, "/tags", Some(&bob), json!({"name": "hw1"})).status(), Status::Ok);
+
+    let response = post_json(&client, "/thread/create", Some(&alice), json!({"title": "Loops", "tag": "hw2", "content": "Help"}));
+    assert_eq!(field_errors(response), vec!["tags"]);
+    post_thread(&client, &alice, "Loops", "HW1", "Help");
+    assert_eq!(tag_counts(&client, &alice), vec![(String::from("hw1"), 1)]);
+}
+
+#[test]
+fn legacy_tags_are_merged() {
+    let mut conn = legacy_db(
+        "INSERT INTO threads (title, creator_uid, tag, content) VALUES ('a', 1, 'HW1', 'x'), ('b', 1, ' hw1', 'x'), ('c', 1, 'Homework 3', 'x');"
+    );
+    migrations::migrate(&mut conn, false).unwrap();
+
+    let mut tags_query = conn.prepare(
+        "SELECT tags.name, COUNT(*) FROM tags JOIN thread_tags ON thread_tags.tag_id = tags.unique_id GROUP BY tags.name ORDER BY tags.name"
+    ).unwrap();
+    let tags: Vec<(String, i64)> = tags_query.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().map(|tag| tag.unwrap()).collect();
+    assert_eq!(tags, vec![(String::from("homework-3"), 1), (String::from("hw1"), 2)]);
+}
+
+// Deletion
+#[test]
+fn author_deletes_thread_and_its_comments() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    create_thread(&client, &key, "First question");
+    create_comment(&client, &key, 1);
+
+    let response = delete_with_key(&client, "/thread/1", &key);
+    assert_eq!(response.status(), Status::Ok);
+    assert!(threads(&client, &key).is_empty());
+
+    let remaining: i64 = db_conn(&client)
+        .query_row("SELECT COUNT(*) FROM comments", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(remaining, 0);
+}
+
+#[test]
+fn other_user_cannot_delete_thread() {
+    let client = client();
+    let alice = register_and_login(&client, "alice");
+    let bob = register_and_login(&client, "bob");
+    create_thread(&client, &alice, "First question");
+
+    assert_error(delete_with_key(&client, "/thread/1", &bob), Status::Forbidden, "forbidden");
+    assert_eq!(threads(&client, &alice).len(), 1);
+}
+
+#[test]
+fn staff_can_delete_thread() {
+    let client = client();
+    let alice = register_and_login(&client, "alice");
+    let ta = register_and_login(&client, "staff");
+    create_thread(&client, &alice, "First question");
+    grant(&client, "staff", privileges::TA);
+
+    assert_eq!(delete_with_key(&client, "/thread/1", &ta).status(), Status::Ok);
+    assert!(threads(&client, &alice).is_empty());
+}
+
+#[test]
+fn delete_missing_thread_not_found() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    assert_error(delete_with_key(&client, "/thread/42", &key), Status::NotFound, "not_found");
+}
+
+#[test]
+fn comment_deletion_checks_ownership() {
+    let client = client();
+    let alice = register_and_login(&client, "alice");
+    let bob = register_and_login(&client, "bob");
+    create_thread(&client, &alice, "First question");
+    create_comment(&client, &alice, 1);
+
+    assert_error(delete_with_key(&client, "/thread/1/comment/1", &bob), Status::Forbidden, "forbidden");
+    assert_error(delete_with_key(&client, "/thread/1/comment/2", &alice), Status::NotFound, "not_found");
+    assert_eq!(delete_with_key(&client, "/thread/1/comment/1", &alice).status(), Status::Ok);
+    assert!(comments(&client, &alice, 1).is_empty());
+}
+
+// Editing & Revisions
+#[test]
+fn edit_thread_records_revision() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    create_thread(&client, &key, "Frist question");
+
+    let response = patch_json(&client, "/thread/1", &key, json!({"title": "First question"}));
+    assert_eq!(response.status(), Status::Ok);
+
+    let listed = threads(&client, &key);
+    assert_eq!(listed[0]["title"], "First question");
+    assert_eq!(listed[0]["content"], "How do I start?");
+    assert!(listed[0]["edited_at"].is_string());
+
+    let body = json_body(get_with_key(&client, "/thread/1/revisions", &key));
+    let revisions = body["revisions"].as_array().unwrap();
+    assert_eq!(revisions.len(), 2);
+    assert_eq!(revisions[0]["title"], "Frist question");
+    assert!(revisions[0]["diff"].is_null());
+    assert_eq!(revisions[1]["diff"]["title"], json!(["- Frist question", "+ First question"]));
+    assert!(revisions[1]["diff"]["tag"].is_null());
+}
+
+#[test]
+fn edit_thread_requires_changes_and_ownership() {
+    let client = client();
+    let alice = register_and_login(&client, "alice");
+    let bob = register_and_login(&client, "bob");
+    create_thread(&client, &alice, "First question");
+
+    assert_error(patch_json(&client, "/thread/1", &alice, json!({})), Status::UnprocessableEntity, "validation_failed");
+    assert_error(patch_json(&client, "/thread/1", &bob, json!({"title": "Mine now"})), Status::Forbidden, "forbidden");
+    assert_error(patch_json(&client, "/thread/42", &alice, json!({"title": "Gone"})), Status::NotFound, "not_found");
+}
+
+#[test]
+fn edit_comment_records_revision() {
+    let client = client();
+    let alice = register_and_login(&client, "alice");
+    let bob = register_and_login(&client, "bob");
+    create_thread(&client, &alice, "First question");
+    create_comment(&client, &alice, 1);
+
+    assert_error(patch_json(&client, "/thread/1/comment/1", &bob, json!({"content": "Nope"})), Status::Forbidden, "forbidden");
+    let response = patch_json(&client, "/thread/1/comment/1", &alice, json!({"content": "Read the handout twice"}));
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(comments(&client, &alice, 1)[0]["content"], "Read the handout twice");
+
+    let body = json_body(get_with_key(&client, "/thread/1/comment/1/revisions", &alice));
+    let revisions = body["revisions"].as_array().unwrap();
+    assert_eq!(revisions.len(), 2);
+    assert_eq!(revisions[1]["version"], 2);
+    assert_eq!(revisions[1]["diff"]["content"], json!(["- Read the handout", "+ Read the handout twice"]));
+}
+
+#[test]
+fn revisions_for_missing_content_not_found() {
+    let client = client();
+    let key = register_and_login(&client, "alice");
+    assert_error(get_with_key(&client, "/thread/42/revisions", &key), Status::NotFound, "not_found");
+    assert_error(get_with_key(&client, "/thread/42/comment/1/revisions", &key), Status::NotFound, "not_found");
+}
+
+// Diffs keep every line of both texts, in order, with as few changes as possible
+fn assert_valid_diff(old: &[&str], new: &[&str]) {
+    let diff = line_diff(&old.join("\n"), &new.join("\n"));
+    let side = |keep: &str| -> Vec<String> {
+        diff.iter().filter(|line| line.starts_with("  ") || line.starts_with(keep)).map(|line| line[2..].to_string()).collect()
+    };
+    assert_eq!(side("- "), old);
+    assert_eq!(side("+ "), new);
+
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    let unchanged = diff.iter().filter(|line| line.starts_with("  ")).count();
+    assert_eq!(unchanged, lcs[0][0], "{:?} -> {:?}: {:?}", old, new, diff);
+}
+
+#[test]
+fn line_diffs_are_minimal() {
+    assert_eq!(line_diff("a\nb\nc", "a\nx\nc"), vec!["  a", "- b", "+ x", "  c"]);
+    assert_eq!(line_diff("", "a"), vec!["+ a"]);
+
+    // Small texts over a tiny alphabet have plenty of ways to line up
+    let mut seed: u32 = 7;
+    let mut text = |length: u32| -> Vec<&str> {
+        (0..length).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            ["a", "b", "c"][(seed >> 16) as usize % 3]
+        }).collect()
+    };
+    for round in 0..300 {
+        let old = text(round % 13);
+        let new = text(round % 11);
+        assert_valid_diff(&old, &new);
+    }
+}
+
+#[test]
+fn large_line_diffs_stay_cheap() {
+    // Two completely different maximum-size posts, one character per line
+    let old = "a\n".repeat(10000);
+    let new = "b\n".repeat(10000);
+    let diff = line_diff(&old, &new);
+    assert_eq!(diff.len(), 20000);
+    assert!(diff[..10000].iter().all(|line| line == "- a"));
+}
@@ -0,0 +1,172 @@
+use crate::config::PasswordPolicyConfig;
+use crate::errors::{AppError, AppResult, FieldError};
+
+// Username rules
+pub const USERNAME_MIN_LENGTH: usize = 3;
+pub const USERNAME_MAX_LENGTH: usize = 32;
+pub const DISPLAY_NAME_MAX_LENGTH: usize = 50;
+
+// Email addresses can't be longer than this (RFC 5321)
+const EMAIL_MAX_LENGTH: usize = 254;
+
+// Commonly used passwords, one per line (bundled so that no network lookup is needed)
+const COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+
+// Registration details that passed validation, normalized for storage
+pub struct Registration {
+    pub username: String,
+    pub email: String,
+    pub password: String,
+}
+
+// Validates every registration field, reporting all of the problems at once
+pub fn validate_registration(username: &str, email: &str, password: &str, policy: &PasswordPolicyConfig) -> AppResult<Registration> {
+    let username = username.trim();
+    let email = normalize_email(email);
+
+    let mut errors: Vec<FieldError> = Vec::new();
+    if let Some(message) = check_username(username) {
+        errors.push(FieldError::new("username", &message));
+    }
+    if let Some(message) = check_email(&email) {
+        errors.push(FieldError::new("email", message));
+    }
+    if let Some(message) = check_password(password, username, policy) {
+        errors.push(FieldError::new("password", &message));
+    }
+
+    if !errors.is_empty() {
+        return Err(AppError::InvalidFields(errors));
+    }
+
+    Ok(Registration {
+        username: username.to_string(),
+        email,
+        password: password.to_string(),
+    })
+}
+
+fn check_username(username: &str) -> Option<String> {
+    // Letters, digits and a few separators, starting with a letter or digit
+    let length = username.chars().count();
+    if !(USERNAME_MIN_LENGTH..=USERNAME_MAX_LENGTH).contains(&length) {
+        return Some(format!("Username must be between {} and {} characters", USERNAME_MIN_LENGTH, USERNAME_MAX_LENGTH));
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.') {
+        return Some(String::from("Username may only contain letters, digits, '_', '-' and '.'"));
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Some(String::from("Username must start with a letter or digit"));
+    }
+
+    None
+}
+
+// Display names are free-form, but stored trimmed and without control characters
+pub fn check_display_name(display_name: &str) -> Option<String> {
+    if display_name.chars().count() > DISPLAY_NAME_MAX_LENGTH {
+        return Some(format!("Display name must be at most {} characters", DISPLAY_NAME_MAX_LENGTH));
+    }
+    if display_name.chars().any(char::is_control) {
+        return Some(String::from("Display name must not contain control characters"));
+    }
+
+    None
+}
+
+// Emails are compared and stored without surrounding whitespace and in lowercase
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+// Tags are compared and stored by canonical name: lowercase, with runs of whitespace and
+// underscores turned into a single '-' ("Homework 3" becomes "homework-3")
+pub fn normalize_tag(tag: &str) -> String {
+    tag.split(|c: char| c.is_whitespace() || c == '_')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+        .to_lowercase()
+}
+
+// Checks a canonical tag name (as produced by normalize_tag)
+pub fn check_tag(tag: &str, max_length: usize) -> Option<String> {
+    if tag.is_empty() {
+        return Some(String::from("Tags must not be empty"));
+    }
+    if tag.chars().count() > max_length {
+        return Some(format!("Tags must be at most {} characters", max_length));
+    }
+    if !tag.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '.' || c == '+' || c == '#') {
+        return Some(format!("Tag '{}' may only contain letters, digits, '-', '.', '+' and '#'", tag));
+    }
+
+    None
+}
+
+// Tag colors are #rrggbb hex codes
+pub fn check_color(color: &str) -> Option<&'static str> {
+    let valid = color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit());
+    match valid {
+        true => None,
+        false => Some("Color must be a hex code like #1f77b4"),
+    }
+}
+
+fn check_email(email: &str) -> Option<&'static str> {
+    if email.is_empty() {
+        return Some("Email must not be empty");
+    }
+    if email.len() > EMAIL_MAX_LENGTH {
+        return Some("Email is too long");
+    }
+
+    // Exactly one '@', with something on either side
+    let (local, domain) = match email.split_once('@') {
+        Some((local, domain)) if !local.is_empty() && !domain.contains('@') => (local, domain),
+        _ => return Some("Email must look like name@example.com"),
+    };
+    if local.len() > 64 || local.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Some("Email must look like name@example.com");
+    }
+
+    // The domain needs at least two dot-separated labels made of letters, digits and hyphens
+    let labels: Vec<&str> = domain.split('.').collect();
+    let valid_label = |label: &&str| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if labels.len() < 2 || !labels.iter().all(valid_label) {
+        return Some("Email must look like name@example.com");
+    }
+
+    None
+}
+
+// Checks a new password against the configured policy (also used when passwords change)
+pub fn check_password(password: &str, username: &str, policy: &PasswordPolicyConfig) -> Option<String> {
+    let length = password.chars().count();
+    if length < policy.min_length {
+        return Some(format!("Password must be at least {} characters", policy.min_length));
+    }
+    if length > policy.max_length {
+        return Some(format!("Password must be at most {} characters", policy.max_length));
+    }
+    if password.eq_ignore_ascii_case(username) {
+        return Some(String::from("Password must not be the same as the username"));
+    }
+    if policy.reject_common && is_common_password(password) {
+        return Some(String::from("Password is too common"));
+    }
+
+    None
+}
+
+fn is_common_password(password: &str) -> bool {
+    COMMON_PASSWORDS
+        .lines()
+        .map(|line| line.trim())
+        .any(|common| !common.is_empty() && common.eq_ignore_ascii_case(password))
+}